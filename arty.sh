@@ -1,2 +1 @@
-cp BountyData.sav ArtyTrial.sav
-cargo run -- ArtyTrial.sav -s ArtyTrial.lua -r
+cargo run -- BountyData.sav -s ArtyTrial.lua -r -o ArtyTrial.sav
@@ -0,0 +1,204 @@
+//! Times loading a save's lua state into lua, the way the library does it
+//! now and the way it did before the borrowing parser.
+//!
+//! ```text
+//! cargo bench --no-default-features --bench load [-- path/to/save.sav]
+//! ```
+//!
+//! Without a save, a synthetic one about the size of a late-game state is
+//! used; pass a real save for numbers that mean something. Both paths start
+//! from the file's lz4 block and end with the globals in a fresh interpreter:
+//!
+//! - now: hadesfile::read, which decompresses exactly and measures the blob by
+//!   skipping over it, then luastate::load, which decodes it in one borrowed
+//!   pass and hands the values to lua.
+//! - before: decompress into the game's fixed-size buffer, measure the blob by
+//!   loading a copy of it into a throwaway interpreter, copy it out, then load
+//!   it into lua again; `old` below is that code, minus its debug output.
+//!
+//! Reading is timed on its own too, since that's the part the borrowing
+//! parser replaced. Loading into lua now also records each table's key order
+//! and array/hash split (see luavalue), which the old load didn't, so the
+//! whole load is not expected to be faster.
+
+use hadessave::{hadesfile, luabins, luastate, HadesSave, LuabinsTable, LuabinsValue};
+use rlua::Lua;
+use serde_json::json;
+use std::borrow::Cow;
+use std::hint::black_box;
+use std::time::{Duration, Instant};
+
+const RUNS: u32 = 10;
+
+mod old {
+    use rlua::{Context, Lua, Table, Value};
+
+    const LUABINS_CNIL: u8 = 0x2D;
+    const LUABINS_CFALSE: u8 = 0x30;
+    const LUABINS_CTRUE: u8 = 0x31;
+    const LUABINS_CNUMBER: u8 = 0x4E;
+    const LUABINS_CSTRING: u8 = 0x53;
+    const LUABINS_CTABLE: u8 = 0x54;
+
+    fn bytes<'a>(loadstate: &mut &'a [u8], len: usize) -> &'a [u8] {
+        let (bytes, rest) = loadstate.split_at(len);
+        *loadstate = rest;
+        bytes
+    }
+
+    fn u32(loadstate: &mut &[u8]) -> u32 {
+        u32::from_le_bytes(bytes(loadstate, 4).try_into().unwrap())
+    }
+
+    fn load_table<'lua>(loadstate: &mut &[u8], context: Context<'lua>) -> Table<'lua> {
+        let array_size = u32(loadstate);
+        let hash_size = u32(loadstate);
+        let table = context.create_table().unwrap();
+        for _ in 0..(array_size + hash_size) {
+            let key = load_value(loadstate, context);
+            let value = load_value(loadstate, context);
+            table.set(key, value).unwrap();
+        }
+        table
+    }
+
+    fn load_value<'lua>(loadstate: &mut &[u8], context: Context<'lua>) -> Value<'lua> {
+        match bytes(loadstate, 1)[0] {
+            LUABINS_CNIL => Value::Nil,
+            LUABINS_CFALSE => Value::Boolean(false),
+            LUABINS_CTRUE => Value::Boolean(true),
+            LUABINS_CNUMBER => {
+                let float = f64::from_le_bytes(bytes(loadstate, 8).try_into().unwrap());
+                if float.fract() == 0.0 {
+                    Value::Integer(float.trunc() as i64)
+                } else {
+                    Value::Number(float)
+                }
+            },
+            LUABINS_CSTRING => {
+                let len = u32(loadstate) as usize;
+                Value::String(context.create_string(bytes(loadstate, len)).unwrap())
+            },
+            LUABINS_CTABLE => Value::Table(load_table(loadstate, context)),
+            tbyte => panic!("unknown type {}", tbyte)
+        }
+    }
+
+    pub fn load<'lua>(loadstate: &mut &[u8], context: Context<'lua>) -> Vec<Value<'lua>> {
+        let num_items = bytes(loadstate, 1)[0];
+        (0..num_items).map(|_| load_value(loadstate, context)).collect()
+    }
+
+    pub fn size(loadstate: &[u8]) -> usize {
+        Lua::new().context(|context| {
+            let copy = loadstate.to_owned();
+            let rest = &mut copy.as_slice();
+            load(rest, context);
+            loadstate.len() - rest.len()
+        })
+    }
+
+    pub fn read_lua_state(lua_state_lz4: &[u8]) -> Vec<u8> {
+        let lua_state = lz4::block::decompress(lua_state_lz4, Some(9388032)).unwrap();
+        let lua_size = size(&lua_state);
+        lua_state[0..lua_size].to_vec()
+    }
+}
+
+fn string(s: &str) -> LuabinsValue<'static> {
+    LuabinsValue::String(Cow::Owned(s.as_bytes().to_vec()))
+}
+
+// Tens of thousands of small tables of strings and numbers, like the
+// records a late-game GameState accumulates.
+fn synthetic_save() -> Vec<u8> {
+    let mut records = Vec::new();
+    for i in 0..40000 {
+        let record = LuabinsTable {
+            array_size: 0,
+            hash_size: 3,
+            entries: vec![
+                (string("Name"), string(&format!("EncounterRecord{}", i))),
+                (string("Count"), LuabinsValue::Number(i as f64)),
+                (string("Cleared"), LuabinsValue::Boolean(i % 3 == 0))
+            ]
+        };
+        records.push((string(&format!("Record{}", i)), LuabinsValue::Table(record)));
+    }
+    let game_state = LuabinsTable { array_size: 0, hash_size: records.len() as i32, entries: records };
+    let globals = LuabinsTable {
+        array_size: 0,
+        hash_size: 1,
+        entries: vec![(string("GameState"), LuabinsValue::Table(game_state))]
+    };
+    let mut lua_state = Vec::new();
+    luabins::encode(&mut lua_state, &[LuabinsValue::Table(globals)]).unwrap();
+
+    let header = json!({
+        "version": 17,
+        "timestamp": 0,
+        "location": "Bench",
+        "unknown0": 0,
+        "unknown1": 0,
+        "unknown2": 0,
+        "runs": 100,
+        "god_mode_enabled": false,
+        "hell_mode_enabled": false,
+        "lua_keys": ["GameState"],
+        "current_map_name": "Hub_Main",
+        "start_next_map": "Hub_Main"
+    });
+    let save = HadesSave::from_header_json(&header, lua_state).unwrap();
+    hadesfile::write(&save, false).unwrap().0
+}
+
+// The fastest of RUNS runs.
+fn time<F: FnMut()>(mut f: F) -> Duration {
+    (0..RUNS)
+        .map(|_| {
+            let start = Instant::now();
+            f();
+            start.elapsed()
+        })
+        .min()
+        .unwrap()
+}
+
+fn main() {
+    // cargo bench passes --bench; anything else is the save to use.
+    let file = match std::env::args().skip(1).find(|arg| !arg.starts_with("--")) {
+        Some(path) => hadessave::read_file(&path).unwrap(),
+        None => synthetic_save()
+    };
+    let save = hadesfile::read(&mut file.as_slice(), true).unwrap();
+    // The lz4 block is the last thing in the file.
+    let lua_state_lz4 = &file[file.len() - save.compressed_size().unwrap()..];
+    assert_eq!(old::read_lua_state(lua_state_lz4), save.lua_state());
+    println!("{} bytes, {} bytes of lua state", file.len(), save.lua_state().len());
+
+    let read_now = time(|| {
+        black_box(hadesfile::read(&mut file.as_slice(), true).unwrap());
+    });
+    let read_before = time(|| {
+        black_box(old::read_lua_state(lua_state_lz4));
+    });
+    let load_now = time(|| {
+        let save = hadesfile::read(&mut file.as_slice(), true).unwrap();
+        let lua = Lua::new();
+        luastate::initialize(&lua, save.version()).unwrap();
+        luastate::load(&lua, &save).unwrap();
+        black_box(lua);
+    });
+    let load_before = time(|| {
+        let lua_state = old::read_lua_state(lua_state_lz4);
+        let lua = Lua::new();
+        lua.context(|context| {
+            black_box(old::load(&mut lua_state.as_slice(), context));
+        });
+        black_box(lua);
+    });
+
+    println!("                now          before");
+    println!("read            {:<12?} {:?}", read_now, read_before);
+    println!("read + load     {:<12?} {:?}", load_now, load_before);
+}
@@ -1,2 +1 @@
-cp H2C1.sav C2Gramps.sav
-cargo run -- C2Gramps.sav -s C2Gramps.lua -r
\ No newline at end of file
+cargo run -- H2C1.sav -s C2Gramps.lua -r -o C2Gramps.sav
\ No newline at end of file
@@ -1,2 +1 @@
-cp BountyData.sav MorosTrial.sav
-cargo run -- MorosTrial.sav -s MorosTrial.lua -r
+cargo run -- BountyData.sav -s MorosTrial.lua -r -o MorosTrial.sav
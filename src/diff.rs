@@ -0,0 +1,79 @@
+use anyhow::{Context as _, Result};
+use hadessave::luabins::{self, LuabinsValue};
+use hadessave::luadiff::{self, Change};
+use hadessave::luajson;
+use serde_json::{json, Value};
+use std::path::Path;
+
+// Whether the path is one of the prefixes or inside one, matching whole keys:
+// GameState.Resources covers GameState.Resources.Gems but not
+// GameState.ResourcesSpent.
+fn matches_prefix(path: &str, prefixes: &[String]) -> bool {
+    prefixes.iter().any(|prefix| {
+        match path.strip_prefix(prefix.as_str()) {
+            Some(rest) => prefix.is_empty() || rest.is_empty() || rest.starts_with('.') || rest.starts_with('['),
+            None => false
+        }
+    })
+}
+
+// Values as in the human json, or the lossless json if the human one can't
+// show them.
+fn display(value: &LuabinsValue) -> String {
+    match luajson::dump_human_value(value) {
+        Ok(json_value) => json_value.to_string(),
+        Err(_) => luajson::dump_value(value).to_string()
+    }
+}
+
+// Prints the differences from the old save to the new one. Header fields are
+// left out when only some paths are asked for.
+pub fn diff(old_path: &Path, new_path: &Path, format: &str, prefixes: &[String], ignore_checksum: bool) -> Result<()> {
+    let old_save = hadessave::open(old_path, ignore_checksum).with_context(|| old_path.display().to_string())?;
+    let new_save = hadessave::open(new_path, ignore_checksum).with_context(|| new_path.display().to_string())?;
+    let old_state = luabins::decode(&mut old_save.lua_state()).with_context(|| old_path.display().to_string())?;
+    let new_state = luabins::decode(&mut new_save.lua_state()).with_context(|| new_path.display().to_string())?;
+
+    let header_differences = if prefixes.is_empty() {
+        luadiff::header_diff(&old_save, &new_save)
+    } else {
+        Vec::new()
+    };
+    let differences: Vec<(String, Change)> = luadiff::diff(&old_state, &new_state).into_iter()
+        .map(|difference| (luabins::path_string(&difference.path), difference))
+        .filter(|(path, _)| prefixes.is_empty() || matches_prefix(path, prefixes))
+        .map(|(path, difference)| (path, difference.change))
+        .collect();
+
+    if format == "json" {
+        let header: Vec<Value> = header_differences.iter()
+            .map(|(field, old_value, new_value)| json!({ "field": field, "old": old_value, "new": new_value }))
+            .collect();
+        let lua_state: Vec<Value> = differences.iter()
+            .map(|(path, change)| match change {
+                Change::Added(new_value) => json!({ "path": path, "change": "added", "new": luajson::dump_value(new_value) }),
+                Change::Removed(old_value) => json!({ "path": path, "change": "removed", "old": luajson::dump_value(old_value) }),
+                Change::Changed(old_value, new_value) => json!({
+                    "path": path,
+                    "change": "changed",
+                    "old": luajson::dump_value(old_value),
+                    "new": luajson::dump_value(new_value)
+                })
+            })
+            .collect();
+        println!("{}", json!({ "header": header, "lua_state": lua_state }));
+        return Ok(());
+    }
+
+    for (field, old_value, new_value) in header_differences.iter() {
+        println!("~ header.{}: {} -> {}", field, old_value, new_value);
+    }
+    for (path, change) in differences.iter() {
+        match change {
+            Change::Added(new_value) => println!("+ {} = {}", path, display(new_value)),
+            Change::Removed(old_value) => println!("- {} = {}", path, display(old_value)),
+            Change::Changed(old_value, new_value) => println!("~ {}: {} -> {}", path, display(old_value), display(new_value))
+        }
+    }
+    Ok(())
+}
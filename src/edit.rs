@@ -0,0 +1,47 @@
+use crate::luapath::{lua_get_path, lua_path_from_string, lua_set_path, lua_to_string};
+use crate::print_warnings;
+
+use anyhow::{Context as _, Result};
+use hadessave::{luajson, luastate, luavalue, HadesSave};
+use rlua::{Lua, Value};
+use std::path::Path;
+
+fn load(path: &Path, ignore_checksum: bool) -> Result<(Lua, HadesSave)> {
+    let lua = Lua::new();
+    let savedata = hadessave::open(path, ignore_checksum)?;
+    luastate::initialize(&lua, savedata.version())?;
+    luastate::load(&lua, &savedata)?;
+    Ok((lua, savedata))
+}
+
+// Prints the value at the path: tables as json, anything else as lua would
+// write it, so the output can be given back to set.
+pub fn get(path: &Path, lua_path: &str, ignore_checksum: bool) -> Result<()> {
+    let lua_path = lua_path_from_string(lua_path)?;
+    let (lua, _savedata) = load(path, ignore_checksum)?;
+    lua.context(|lua_ctx| -> Result<()> {
+        match lua_get_path(lua_ctx, lua_path)? {
+            Value::Table(table) => {
+                let value = luavalue::from_lua(lua_ctx, Value::Table(table), 0)?;
+                println!("{}", serde_json::to_string_pretty(&luajson::dump_human_value(&value)?)?);
+            },
+            value => println!("{}", lua_to_string(value, lua_ctx)?)
+        }
+        Ok(())
+    })
+}
+
+// Sets the value at the path to the result of a lua expression, evaluated
+// with the save loaded, and writes the save to output.
+pub fn set(path: &Path, output: &Path, lua_path: &str, expression: &str, ignore_checksum: bool, force: bool) -> Result<()> {
+    let lua_path = lua_path_from_string(lua_path)?;
+    let (lua, mut savedata) = load(path, ignore_checksum)?;
+    lua.context(|lua_ctx| -> Result<()> {
+        let value: Value = lua_ctx.load(&format!("return {}", expression)).eval().context("expression")?;
+        lua_set_path(lua_ctx, &lua_path, value)
+    })?;
+    let mut warnings = luastate::save(&lua, &mut savedata)?;
+    warnings.extend(hadessave::save(output, &savedata, force)?);
+    print_warnings(&warnings);
+    Ok(())
+}
@@ -0,0 +1,128 @@
+//! The ways reading and writing a save can fail.
+
+use crate::luabins::LuabinsError;
+
+use std::fmt;
+use std::io;
+
+pub type Result<T> = std::result::Result<T, SaveError>;
+
+#[derive(Debug)]
+pub enum SaveError {
+  Io(io::Error),
+  /// The file doesn't start with the SGB1 signature.
+  BadSignature,
+  /// A version with no known layout, in a file where the lua state could not
+  /// be found, or as the source or target of a conversion.
+  UnsupportedVersion(u32),
+  /// The data ends in the middle of a value. The offset is from the start of
+  /// the file, when the truncation is in the file itself.
+  Truncated { offset: Option<usize>, needed: usize, available: usize },
+  ChecksumMismatch { expected: u32, actual: u32 },
+  /// A header string that isn't valid utf-8.
+  BadHeaderField(&'static str),
+  /// The lz4 block holding the lua state is corrupt.
+  Decompression(String),
+  /// The lua state doesn't fit in the buffer the game allocates for it.
+  StateTooLarge {
+    size: usize,
+    limit: usize,
+    /// Top-level globals by serialized size, largest first.
+    largest: Vec<(String, usize)>
+  },
+  /// A luabins blob that can't be decoded, located by byte offset into the
+  /// blob and by lua path.
+  Luabins { offset: usize, path: String, error: LuabinsError },
+  /// Values that can't be written as luabins.
+  Unencodable(LuabinsError),
+  /// A json document (a dump, a header or a patch) without the expected
+  /// shape, or a value the human json encoding can't show. The path locates
+  /// the value in the document, like `lua_state[0].entries[3][1]`.
+  Json { path: String, message: String },
+  /// A patch that can't be made or applied; see luapatch.
+  Patch(String),
+  Lua(rlua::Error)
+}
+
+impl SaveError {
+  pub(crate) fn json<M: Into<String>>(message: M) -> SaveError {
+    SaveError::Json { path: String::new(), message: message.into() }
+  }
+
+  /// Places a json error inside the value at segment, which is a key or an
+  /// `[index]`. Other errors are returned as they are.
+  pub(crate) fn within(self, segment: &str) -> SaveError {
+    match self {
+      SaveError::Json { path, message } => {
+        let path = if path.is_empty() || path.starts_with('[') {
+          format!("{}{}", segment, path)
+        } else {
+          format!("{}.{}", segment, path)
+        };
+        SaveError::Json { path: path, message: message }
+      },
+      err => err
+    }
+  }
+}
+
+impl fmt::Display for SaveError {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    match self {
+      SaveError::Io(error) => write!(f, "{}", error),
+      SaveError::BadSignature => write!(f, "not a Hades save file"),
+      SaveError::UnsupportedVersion(version) => write!(f, "unsupported save version {}", version),
+      SaveError::Truncated { offset, needed, available } => {
+        write!(f, "not enough data (needed {} bytes, {} available)", needed, available)?;
+        if let Some(offset) = offset {
+          write!(f, " at offset {:#x}", offset)?;
+        }
+        Ok(())
+      },
+      SaveError::ChecksumMismatch { expected, actual } => {
+        write!(f, "checksum mismatch (expected {:08x}, actual {:08x})", expected, actual)
+      },
+      SaveError::BadHeaderField(field) => write!(f, "{}: not valid utf-8", field),
+      SaveError::Decompression(message) => write!(f, "corrupt lz4 block: {}", message),
+      SaveError::StateTooLarge { size, limit, largest } => {
+        write!(f, "lua state is too large ({} bytes, limit {})", size, limit)?;
+        for (name, size) in largest.iter().take(10) {
+          write!(f, "\n  {} {}", name, size)?;
+        }
+        Ok(())
+      },
+      SaveError::Luabins { offset, path, error } => {
+        write!(f, "{} at offset {:#x}", error, offset)?;
+        if !path.is_empty() {
+          write!(f, " ({})", path)?;
+        }
+        Ok(())
+      },
+      SaveError::Unencodable(error) => write!(f, "can't encode the lua state: {}", error),
+      SaveError::Json { path, message } => {
+        if path.is_empty() {
+          write!(f, "{}", message)
+        } else {
+          write!(f, "{}: {}", path, message)
+        }
+      },
+      SaveError::Patch(message) => write!(f, "{}", message),
+      SaveError::Lua(error) => write!(f, "{}", error)
+    }
+  }
+}
+
+// The display already includes any underlying error, so there's no source.
+impl std::error::Error for SaveError {}
+
+impl From<io::Error> for SaveError {
+  fn from(error: io::Error) -> SaveError {
+    SaveError::Io(error)
+  }
+}
+
+impl From<rlua::Error> for SaveError {
+  fn from(error: rlua::Error) -> SaveError {
+    SaveError::Lua(error)
+  }
+}
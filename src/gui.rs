@@ -1,9 +1,11 @@
-use crate::hadesfile;
-use crate::luastate;
+use crate::luapath::{lua_get_path, lua_path_as_string, lua_to_string, TableKey};
+use crate::print_warnings;
+use hadessave::hadesfile;
+use hadessave::luastate;
 
-use anyhow::{bail, Result};
+use anyhow::Result;
 use druid::im::Vector;
-use druid::{AppLauncher, Color, Data, Env, Lens, Key, Size, theme, Widget, WidgetExt, WindowDesc};
+use druid::{commands, AppDelegate, AppLauncher, Color, Command, Data, DelegateCtx, Env, FileDialogOptions, Handled, Lens, Key, Size, Target, theme, Widget, WidgetExt, WindowDesc};
 use druid::lens::{self, LensExt};
 use druid::widget::{Button, Flex, Label, List, Scroll, TextBox, Either};
 use hadesfile::HadesSave;
@@ -17,10 +19,11 @@ use std::mem;
 #[derive(Clone, Data, Lens)]
 struct GuiState {
     lua: Rc<Lua>,
-    #[data(ignore)]
+    #[data(same_fn = "PartialEq::eq")]
     path: PathBuf,
     #[data(ignore)]
     savedata: HadesSave,
+    force: bool,
     dirty: bool,
     columns: Vector<Column>,
     lua_path_pointed_by_columns: Vector<TableKey>,
@@ -30,6 +33,15 @@ struct GuiState {
 }
 
 impl GuiState {
+    fn save(self: &mut GuiState) -> Result<()> {
+        let mut warnings = luastate::save(self.lua.as_ref(), &mut self.savedata)?;
+        let (outfile, write_warnings) = hadesfile::write(&self.savedata, self.force)?;
+        fs::write(&self.path, outfile)?;
+        warnings.extend(write_warnings);
+        print_warnings(&warnings);
+        self.dirty = false;
+        Ok(())
+    }
     fn run_command(self: &mut GuiState, command: &str) -> Result<()> {
         self.lua.context(|lua_ctx| -> Result<()> {
             lua_ctx.load(command).exec().map_err(anyhow::Error::new)
@@ -90,11 +102,11 @@ struct Column {
     items: Vector<TableKey>
 }
 
-#[derive(Clone, Data, Debug, PartialEq, PartialOrd, Eq, Ord)]
-enum TableKey {
-    StringKey(String),
-    NumberKey(i64)
-} 
+impl Data for TableKey {
+    fn same(&self, other: &Self) -> bool {
+        self == other
+    }
+}
 
 const LABEL_TEXT_COLOR: Key<Color> = Key::new("paradigmsort.hadessaveeditor.label-text-color");
 
@@ -198,21 +210,18 @@ fn ui_builder() -> impl Widget<GuiState> {
             ).lens(GuiState::dirty), 1.)
             .with_child(Button::new("Save").on_click(|_ctx, state: &mut GuiState, _env| {
                 if state.dirty {
-                    let mut savedata = state.savedata.clone();
-                    let lua_state = luastate::save(state.lua.as_ref()).unwrap(); // TODO
-                    match savedata {
-                        HadesSave::V16(ref mut data) => {
-                            data.lua_state = lua_state
-                        },
-                        HadesSave::V17(ref mut data) => {
-                            data.lua_state = lua_state
-                        }
+                    if let Err(err) = state.save() {
+                        println!("Not saving: {}", err);
                     }
-                    let outfile = hadesfile::write(&savedata).unwrap(); // TODO
-                    fs::write(&state.path, outfile).unwrap(); // TODO
-                    state.dirty = false;
                 }
             }))
+            .with_child(Button::new("Save As").on_click(|ctx, state: &mut GuiState, _env| {
+                let mut options = FileDialogOptions::new().title("Save As");
+                if let Some(file_name) = state.path.file_name() {
+                    options = options.default_name(file_name.to_string_lossy());
+                }
+                ctx.submit_command(Command::new(commands::SHOW_SAVE_PANEL, options, Target::Auto));
+            }))
             .padding(5.);
 
     let path_row =
@@ -282,73 +291,6 @@ fn lua_is_saved_type(value: &Value) -> bool {
     }
 }
 
-fn lua_get_path<'a>(lua_ctx: Context<'a>, lua_path: Vector<TableKey>) -> Result<Value<'a>> {
-    let mut current_value: Value = Value::Table(lua_ctx.globals());
-    for segment in lua_path {
-        match current_value {
-            Value::Table(table_value) => {
-                match segment {
-                    TableKey::NumberKey(i) => {
-                        current_value = table_value.get(i)?;
-                    },
-                    TableKey::StringKey(s) => {
-                        current_value = table_value.get(s)?;
-                    }
-                }
-            },
-            _ => bail!("not a table! {:?}", current_value)
-        }
-    }
-    Ok(current_value)
-}
-
-fn lua_path_as_string(lua_path: &Vector<TableKey>) -> String {
-    let mut path_string: String = "".to_owned();
-    for segment in lua_path {
-        match segment {
-            TableKey::NumberKey(i) => {
-                path_string.push_str(&format!("[{}]", i))
-            },
-            TableKey::StringKey(s) => {
-                if path_string != "" {
-                    path_string.push_str(".");
-                }
-                path_string.push_str(&s);
-            }
-        }
-    }
-    path_string
-}
-
-fn lua_to_string<'a>(value: Value<'a>, lua_ctx: Context<'a>) -> Result<String> {
-    let lua_string = match value {
-        Value::Nil => "nil".to_owned(),
-        Value::Boolean(boolean_value) => {
-            if boolean_value { "true".to_owned() } else { "false".to_owned() }
-        },
-        Value::Integer(_) => {
-            String::from_lua(value, lua_ctx)?
-        },
-        Value::Number(_) => {
-            String::from_lua(value, lua_ctx)?
-        },
-        Value::String(_) => {
-            format!("\"{}\"", String::from_lua(value, lua_ctx)?)
-        },
-        Value::Table(_) => {
-            "table".to_owned()
-        },
-        Value::Function(_) => {
-            "function".to_owned()
-        },
-        Value::Thread(_) => todo!(),
-        Value::UserData(_) => todo!(),
-        Value::LightUserData(_) => todo!(),
-        Value::Error(_) => todo!(),
-    };
-    Ok(lua_string)
-}
-
 fn lua_to_table_key<'a>(value: Value<'a>, lua_ctx: Context<'a>) -> Result<TableKey> {
     let table_key = match value {
         Value::Integer(i) => TableKey::NumberKey(i),
@@ -358,11 +300,31 @@ fn lua_to_table_key<'a>(value: Value<'a>, lua_ctx: Context<'a>) -> Result<TableK
     Ok(table_key)
 }
 
-pub fn gui(lua: Lua, savedata: HadesSave, path: PathBuf) -> Result<()> {
+// Saves to the file picked in the Save As dialog, which then becomes the file
+// Save writes to.
+struct Delegate;
+
+impl AppDelegate<GuiState> for Delegate {
+    fn command(&mut self, _ctx: &mut DelegateCtx, _target: Target, cmd: &Command, data: &mut GuiState, _env: &Env) -> Handled {
+        match cmd.get(commands::SAVE_FILE_AS) {
+            Some(file_info) => {
+                data.path = file_info.path().to_owned();
+                if let Err(err) = data.save() {
+                    println!("Not saving: {}", err);
+                }
+                Handled::Yes
+            },
+            None => Handled::No
+        }
+    }
+}
+
+pub fn gui(lua: Lua, savedata: HadesSave, path: PathBuf, force: bool) -> Result<()> {
     let mut gui_state = GuiState {
         lua: Rc::new(lua),
         path: path,
         savedata: savedata,
+        force: force,
         dirty: false,
         columns: Vector::new(),
         lua_path_pointed_by_columns: Vector::new(),
@@ -380,6 +342,7 @@ pub fn gui(lua: Lua, savedata: HadesSave, path: PathBuf) -> Result<()> {
         .window_size(Size::new(900.0, 800.0));
 
     AppLauncher::with_window(main_window)
+        .delegate(Delegate)
         .launch(gui_state)?;
     Ok(())
 }
\ No newline at end of file
@@ -1,249 +1,874 @@
+//! The save file container: header fields and the compressed lua state.
+
+use crate::error::{Result, SaveError};
 use crate::luabins;
 use crate::read;
 use crate::write;
 
 use adler32::adler32;
-use anyhow::{bail, Context, Result};
 use lz4;
+use serde_json::{json, Value};
 use std::convert::TryInto;
+use std::fmt;
+use std::mem;
+
+
+/// A field that can appear in a save header.
+pub trait HeaderField: Sized {
+  fn read(loadstate: &mut &[u8]) -> Result<Self>;
+  fn write(&self, contents: &mut Vec<u8>);
+  fn to_json(&self) -> Value;
+  fn from_json(value: &Value) -> Result<Self>;
+}
+
+impl HeaderField for u32 {
+  fn read(loadstate: &mut &[u8]) -> Result<Self> {
+    Ok(read::u32(loadstate)?)
+  }
+  fn write(&self, contents: &mut Vec<u8>) {
+    write::u32(contents, *self)
+  }
+  fn to_json(&self) -> Value {
+    json!(self)
+  }
+  fn from_json(value: &Value) -> Result<Self> {
+    value.as_u64()
+      .and_then(|u| u.try_into().ok())
+      .ok_or_else(|| SaveError::json(format!("expected a u32, got {}", value)))
+  }
+}
+
+impl HeaderField for u64 {
+  fn read(loadstate: &mut &[u8]) -> Result<Self> {
+    Ok(read::u64(loadstate)?)
+  }
+  fn write(&self, contents: &mut Vec<u8>) {
+    write::u64(contents, *self)
+  }
+  fn to_json(&self) -> Value {
+    json!(self)
+  }
+  fn from_json(value: &Value) -> Result<Self> {
+    value.as_u64().ok_or_else(|| SaveError::json(format!("expected a u64, got {}", value)))
+  }
+}
+
+impl HeaderField for bool {
+  fn read(loadstate: &mut &[u8]) -> Result<Self> {
+    Ok(read::byte(loadstate)? != 0)
+  }
+  fn write(&self, contents: &mut Vec<u8>) {
+    write::byte(contents, if *self {1} else {0})
+  }
+  fn to_json(&self) -> Value {
+    json!(self)
+  }
+  fn from_json(value: &Value) -> Result<Self> {
+    value.as_bool().ok_or_else(|| SaveError::json(format!("expected a bool, got {}", value)))
+  }
+}
+
+impl HeaderField for String {
+  // Invalid utf-8 is reported without a name; the header fills it in.
+  fn read(loadstate: &mut &[u8]) -> Result<Self> {
+    let size = read::u32(loadstate)?;
+    let str_bytes = read::bytes(loadstate, size.try_into().unwrap())?;
+    String::from_utf8(str_bytes.to_vec()).map_err(|_| SaveError::BadHeaderField(""))
+  }
+  fn write(&self, contents: &mut Vec<u8>) {
+    write::u32(contents, self.len() as u32);
+    write::bytes(contents, self.as_bytes());
+  }
+  fn to_json(&self) -> Value {
+    json!(self)
+  }
+  fn from_json(value: &Value) -> Result<Self> {
+    value.as_str()
+      .map(|s| s.to_owned())
+      .ok_or_else(|| SaveError::json(format!("expected a string, got {}", value)))
+  }
+}
 
-pub trait UncompressedSize {
+impl HeaderField for Vec<String> {
+  fn read(loadstate: &mut &[u8]) -> Result<Self> {
+    let mut strings = Vec::new();
+    let size = read::u32(loadstate)?;
+    for _ in 0..size {
+      strings.push(String::read(loadstate)?);
+    }
+    Ok(strings)
+  }
+  fn write(&self, contents: &mut Vec<u8>) {
+    write::u32(contents, self.len() as u32);
+    for string in self.iter() {
+      string.write(contents)
+    }
+  }
+  fn to_json(&self) -> Value {
+    json!(self)
+  }
+  fn from_json(value: &Value) -> Result<Self> {
+    value.as_array()
+      .ok_or_else(|| SaveError::json(format!("expected a list, got {}", value)))?
+      .iter()
+      .enumerate()
+      .map(|(i, value)| String::from_json(value).map_err(|err| err.within(&format!("[{}]", i))))
+      .collect()
+  }
+}
+
+/// A known save version: its header layout, followed by the lz4 compressed
+/// lua state.
+pub trait SaveVersion: Sized {
+  const VERSION: u32;
+  // Size of the buffer the game decompresses the lua state into.
   const UNCOMPRESSED_SIZE: i32;
+  fn read_body(loadstate: &mut &[u8]) -> Result<Self>;
+  fn write_body(&self, contents: &mut Vec<u8>) -> Result<()>;
+  fn header_to_json(&self) -> Value;
+  fn header_from_json(header: &Value, lua_state: Vec<u8>) -> Result<Self>;
+}
+
+// Describes a save version's header once; the struct, reader and writer are
+// all generated from the field list.
+macro_rules! save_version {
+  ($name:ident, $version:expr, $uncompressed_size:expr, { $($field:ident: $type:ty),* $(,)? }) => {
+    #[derive(Clone)]
+    pub struct $name {
+      $(pub $field: $type,)*
+      pub lua_state: Vec<u8>,
+      /// Whatever followed the luabins blob in the decompressed state; see
+      /// HadesSave::lua_state_tail.
+      pub lua_state_tail: Vec<u8>,
+      /// Size of the lz4 block the lua state was read from; None if it
+      /// wasn't read from a file.
+      pub compressed_size: Option<usize>
+    }
+
+    impl SaveVersion for $name {
+      const VERSION: u32 = $version;
+      const UNCOMPRESSED_SIZE: i32 = $uncompressed_size;
+
+      fn read_body(loadstate: &mut &[u8]) -> Result<Self> {
+        $(let $field = <$type>::read(loadstate).map_err(|err| match err {
+          SaveError::BadHeaderField(_) => SaveError::BadHeaderField(stringify!($field)),
+          err => err
+        })?;)*
+        let (lua_state, lua_state_tail, compressed_size) = read_lua_state(loadstate)?;
+        Ok($name {
+          $($field: $field,)*
+          lua_state: lua_state,
+          lua_state_tail: lua_state_tail,
+          compressed_size: Some(compressed_size)
+        })
+      }
+
+      fn write_body(&self, contents: &mut Vec<u8>) -> Result<()> {
+        $(self.$field.write(contents);)*
+        write_lua_state(contents, &self.lua_state, &self.lua_state_tail)
+      }
+
+      fn header_to_json(&self) -> Value {
+        let mut header = json!({ "version": Self::VERSION });
+        $(header[stringify!($field)] = self.$field.to_json();)*
+        header
+      }
+
+      fn header_from_json(header: &Value, lua_state: Vec<u8>) -> Result<Self> {
+        Ok($name {
+          $($field: <$type>::from_json(&header[stringify!($field)]).map_err(|err| err.within(stringify!($field)))?,)*
+          lua_state: lua_state,
+          lua_state_tail: Vec::new(),
+          compressed_size: None
+        })
+      }
+    }
+  };
+}
+
+save_version!(HadesSaveV16, 16, 9388032, {
+  timestamp: u64,
+  location: String,
+  runs: u32,
+  active_meta_points: u32,
+  active_shrine_points: u32,
+  god_mode_enabled: bool,
+  hell_mode_enabled: bool,
+  lua_keys: Vec<String>,
+  current_map_name: String,
+  start_next_map: String,
+});
+
+// Hades 2
+save_version!(HadesSaveV17, 17, 9388032, {
+  timestamp: u64,
+  location: String,
+  // The 12 bytes between location and runs, read as three u32s. What they
+  // hold hasn't been worked out: they sit where v16 keeps its meta and
+  // shrine point counts, but that's only their position, and there were no
+  // real saves to compare values across. They're named by position until
+  // someone can, and written back exactly as read.
+  unknown0: u32,
+  unknown1: u32,
+  unknown2: u32,
+  runs: u32,
+  god_mode_enabled: bool,
+  hell_mode_enabled: bool,
+  lua_keys: Vec<String>,
+  current_map_name: String,
+  start_next_map: String,
+});
+
+// Early-access Hades saves have versions below 16, but their layouts aren't
+// described here: there are no sample files to work them out from or check
+// them against, so they're read as raw saves like any other unknown version.
+
+/// A save whose version has no known layout. The header is kept as raw bytes
+/// so the file can be written back unchanged apart from the lua state.
+#[derive(Clone)]
+pub struct HadesSaveRaw {
+  pub version: u32,
+  pub header: Vec<u8>,
+  pub lua_state: Vec<u8>,
+  pub lua_state_tail: Vec<u8>,
+  pub compressed_size: Option<usize>
+}
+
+impl HadesSaveRaw {
+  /// Raw saves are checked against the largest buffer of any known version.
+  pub const UNCOMPRESSED_SIZE: i32 = HadesSaveV17::UNCOMPRESSED_SIZE;
 }
 
+/// A save file, as read by [`read()`] and written by [`write()`].
 #[derive(Clone)]
 pub enum HadesSave {
   V16(HadesSaveV16),
-  V17(HadesSaveV17)
+  V17(HadesSaveV17),
+  Raw(HadesSaveRaw)
 }
 
-#[derive(Clone)]
-pub struct HadesSaveV16 {
-  pub timestamp: u64,
-  pub location: String,
-  pub runs: u32,
-  pub active_meta_points: u32,
-  pub active_shrine_points: u32,
-  pub god_mode_enabled: bool,
-  pub hell_mode_enabled: bool,
-  pub lua_keys: Vec<String>,
-  pub current_map_name: String,
-  pub start_next_map: String,
-  pub lua_state: Vec<u8>
+impl HadesSave {
+  pub fn version(&self) -> u32 {
+    match self {
+      HadesSave::V16(_) => HadesSaveV16::VERSION,
+      HadesSave::V17(_) => HadesSaveV17::VERSION,
+      HadesSave::Raw(data) => data.version
+    }
+  }
+
+  /// The decompressed luabins blob; see luabins::decode.
+  pub fn lua_state(&self) -> &[u8] {
+    match self {
+      HadesSave::V16(data) => &data.lua_state,
+      HadesSave::V17(data) => &data.lua_state,
+      HadesSave::Raw(data) => &data.lua_state
+    }
+  }
+
+  /// Replaces the luabins blob. The tail is kept, so a state that is
+  /// re-encoded unchanged is written back exactly as it was read, but cut
+  /// short where it would push a blob that fits past the game's buffer.
+  pub fn set_lua_state(&mut self, lua_state: Vec<u8>) {
+    let limit = self.uncompressed_size() as usize;
+    let (state, tail, compressed_size) = self.lua_state_mut();
+    *state = lua_state;
+    tail.truncate(limit.saturating_sub(state.len()));
+    *compressed_size = None;
+  }
+
+  /// The bytes that followed the luabins blob in the decompressed state, which
+  /// decode doesn't read. Hades II pads its state with zeros; they're kept so
+  /// the state compresses back to the same block. Empty for saves that
+  /// weren't read from a file.
+  pub fn lua_state_tail(&self) -> &[u8] {
+    match self {
+      HadesSave::V16(data) => &data.lua_state_tail,
+      HadesSave::V17(data) => &data.lua_state_tail,
+      HadesSave::Raw(data) => &data.lua_state_tail
+    }
+  }
+
+  pub fn set_lua_state_tail(&mut self, lua_state_tail: Vec<u8>) {
+    let (_, tail, compressed_size) = self.lua_state_mut();
+    *tail = lua_state_tail;
+    *compressed_size = None;
+  }
+
+  fn lua_state_mut(&mut self) -> (&mut Vec<u8>, &mut Vec<u8>, &mut Option<usize>) {
+    match self {
+      HadesSave::V16(data) => (&mut data.lua_state, &mut data.lua_state_tail, &mut data.compressed_size),
+      HadesSave::V17(data) => (&mut data.lua_state, &mut data.lua_state_tail, &mut data.compressed_size),
+      HadesSave::Raw(data) => (&mut data.lua_state, &mut data.lua_state_tail, &mut data.compressed_size)
+    }
+  }
+
+  /// Size of the lz4 block the lua state was read from; None if it wasn't
+  /// read from a file, or has been replaced since.
+  pub fn compressed_size(&self) -> Option<usize> {
+    match self {
+      HadesSave::V16(data) => data.compressed_size,
+      HadesSave::V17(data) => data.compressed_size,
+      HadesSave::Raw(data) => data.compressed_size
+    }
+  }
+
+  /// The header fields as a json object, including the version.
+  pub fn header_json(&self) -> Value {
+    match self {
+      HadesSave::V16(data) => data.header_to_json(),
+      HadesSave::V17(data) => data.header_to_json(),
+      HadesSave::Raw(data) => json!({ "version": data.version, "header": data.header })
+    }
+  }
+
+  /// Builds a save from a json object as produced by header_json.
+  pub fn from_header_json(header: &Value, lua_state: Vec<u8>) -> Result<HadesSave> {
+    let version = u32::from_json(&header["version"]).map_err(|err| err.within("version"))?;
+    let mut save = match version {
+      HadesSaveV16::VERSION => HadesSave::V16(HadesSaveV16::header_from_json(header, lua_state)?),
+      HadesSaveV17::VERSION => HadesSave::V17(HadesSaveV17::header_from_json(header, lua_state)?),
+      _ => HadesSave::Raw(HadesSaveRaw {
+        version: version,
+        header: Vec::new(),
+        lua_state: lua_state,
+        lua_state_tail: Vec::new(),
+        compressed_size: None
+      })
+    };
+    if let HadesSave::Raw(_) = save {
+      save.set_header_json(header)?;
+    }
+    Ok(save)
+  }
+
+  /// Replaces the header fields from a json object as produced by header_json.
+  /// The version can't be changed this way; use convert for that.
+  pub fn set_header_json(&mut self, header: &Value) -> Result<()> {
+    let mut updated = match self {
+      HadesSave::V16(_) => HadesSave::V16(HadesSaveV16::header_from_json(header, Vec::new())?),
+      HadesSave::V17(_) => HadesSave::V17(HadesSaveV17::header_from_json(header, Vec::new())?),
+      HadesSave::Raw(data) => {
+        let raw_header = header["header"].as_array()
+          .ok_or_else(|| SaveError::json(format!("expected a list, got {}", header["header"])))
+          .and_then(|bytes| bytes.iter()
+            .map(|byte| byte.as_u64().and_then(|b| b.try_into().ok()).ok_or_else(|| SaveError::json(format!("expected a byte, got {}", byte))))
+            .collect::<Result<Vec<u8>>>())
+          .map_err(|err| err.within("header"))?;
+        HadesSave::Raw(HadesSaveRaw {
+          version: data.version,
+          header: raw_header,
+          lua_state: Vec::new(),
+          lua_state_tail: Vec::new(),
+          compressed_size: None
+        })
+      }
+    };
+    // The lua state moves across rather than being copied; it can be many
+    // megabytes, and luastate::save comes through here on every save.
+    let (state, tail, compressed_size) = self.lua_state_mut();
+    let (updated_state, updated_tail, updated_compressed_size) = updated.lua_state_mut();
+    mem::swap(updated_state, state);
+    mem::swap(updated_tail, tail);
+    mem::swap(updated_compressed_size, compressed_size);
+    *self = updated;
+    Ok(())
+  }
+
+  pub fn lua_keys(&self) -> Option<&Vec<String>> {
+    match self {
+      HadesSave::V16(data) => Some(&data.lua_keys),
+      HadesSave::V17(data) => Some(&data.lua_keys),
+      HadesSave::Raw(_) => None
+    }
+  }
+
+  pub fn lua_keys_mut(&mut self) -> Option<&mut Vec<String>> {
+    match self {
+      HadesSave::V16(data) => Some(&mut data.lua_keys),
+      HadesSave::V17(data) => Some(&mut data.lua_keys),
+      HadesSave::Raw(_) => None
+    }
+  }
+
+  /// The size of the buffer the game decompresses the lua state into.
+  pub fn uncompressed_size(&self) -> i32 {
+    match self {
+      HadesSave::V16(_) => HadesSaveV16::UNCOMPRESSED_SIZE,
+      HadesSave::V17(_) => HadesSaveV17::UNCOMPRESSED_SIZE,
+      HadesSave::Raw(_) => HadesSaveRaw::UNCOMPRESSED_SIZE
+    }
+  }
 }
 
-impl UncompressedSize for HadesSaveV16 {
-  const UNCOMPRESSED_SIZE: i32 = 9388032;
+// States bigger than the game's buffer are still read, so they can be cut
+// down, but only up to a few times its size: the decompressed size comes
+// from the block itself, and a corrupt one could otherwise claim anything.
+const MAX_DECOMPRESSED_SIZE: usize = 4 * HadesSaveV17::UNCOMPRESSED_SIZE as usize;
+
+// Walks the lz4 block's sequences to find its decompressed size, so the
+// output buffer can be allocated exactly instead of guessed.
+fn lz4_decompressed_size(block: &[u8]) -> Result<usize> {
+  let mut rest = block;
+  let mut size: usize = 0;
+  let extended_length = |rest: &mut &[u8], mut length: usize| -> Result<usize> {
+    loop {
+      let next = read::byte(rest)?;
+      length += next as usize;
+      if next != 255 {
+        return Ok(length);
+      }
+    }
+  };
+  loop {
+    let token = read::byte(&mut rest)?;
+    let mut literals = (token >> 4) as usize;
+    if literals == 15 {
+      literals = extended_length(&mut rest, literals)?;
+    }
+    read::bytes(&mut rest, literals)?;
+    size += literals;
+    if rest.is_empty() {
+      break;
+    }
+    read::bytes(&mut rest, 2)?;
+    let mut match_length = (token & 0x0F) as usize;
+    if match_length == 15 {
+      match_length = extended_length(&mut rest, match_length)?;
+    }
+    size += match_length + 4;
+    if size > MAX_DECOMPRESSED_SIZE {
+      return Err(SaveError::Decompression(format!("decompresses to over {} bytes", MAX_DECOMPRESSED_SIZE)));
+    }
+  }
+  Ok(size)
 }
 
-#[derive(Clone)]
-pub struct HadesSaveV17 { // Hades 2
-  pub timestamp: u64,
-  pub location: String,
-  pub padding1: Vec<u8>, // 12 bytes
-  pub runs: u32,
-  pub god_mode_enabled: bool,
-  pub hell_mode_enabled: bool,
-  pub lua_keys: Vec<String>,
-  pub current_map_name: String,
-  pub start_next_map: String,
-  pub lua_state: Vec<u8>
+// Decompresses the lua state, whatever its size: one too large for the game
+// still opens, so it can be cut down. check_size is enforced on write.
+fn decompress(lua_state_lz4: &[u8]) -> Result<Vec<u8>> {
+  let size = lz4_decompressed_size(lua_state_lz4).map_err(|err| match err {
+    err @ SaveError::Decompression(_) => err,
+    err => SaveError::Decompression(err.to_string())
+  })?;
+  let size_i32 = i32::try_from(size).map_err(|err| SaveError::Decompression(err.to_string()))?;
+  let mut lua_state = vec![0u8; size];
+  let decompressed = lz4::block::decompress_to_buffer(
+    lua_state_lz4,
+    Some(size_i32),
+    &mut lua_state).map_err(|err| SaveError::Decompression(err.to_string()))?;
+  lua_state.truncate(decompressed);
+  Ok(lua_state)
 }
 
 
-fn read_string(loadstate: &mut &[u8]) -> Result<String> {
-  let size = read::u32(loadstate).context("size")?;
-  let str_bytes = read::bytes(loadstate, size.try_into().unwrap()).context("bytes")?;
-  String::from_utf8(str_bytes.to_vec()).context("utf8")
+// Returns the luabins blob, whatever follows it, and the size of the lz4
+// block. A state that doesn't decode is returned whole, so decoding it later
+// reports where it's broken.
+fn read_lua_state(loadstate: &mut &[u8]) -> Result<(Vec<u8>, Vec<u8>, usize)> {
+  let lua_state_size = read::u32(loadstate)?;
+  let lua_state_lz4 = read::bytes(loadstate, lua_state_size.try_into().unwrap())?;
+  let mut lua_state = decompress(lua_state_lz4)?;
+  let lua_state_tail = match luabins::size(&lua_state) {
+    Ok(size) => lua_state.split_off(size),
+    Err(_) => Vec::new()
+  };
+  Ok((lua_state, lua_state_tail, lua_state_lz4.len()))
 }
 
-pub fn read(loadstate: &mut &[u8]) -> Result<HadesSave> {
-  let signature = read::bytes(loadstate, 4).context("signature")?;
-  if signature != "SGB1".as_bytes() {
-    bail!("Not a Hades save file");
-  }
-  let _checksum = read::bytes(loadstate, 4).context("checksum")?;
-  let version = read::u32(loadstate).context("version")?;
-  if version == 16 {
-    return Ok(HadesSave::V16(read_v16(loadstate)?));
-  } else if version == 17 {
-    return Ok(HadesSave::V17(read_v17(loadstate)?));
+fn write_lua_state(contents: &mut Vec<u8>, lua_state: &[u8], lua_state_tail: &[u8]) -> Result<()> {
+  let lua_state_lz4 = if lua_state_tail.is_empty() {
+    lz4::block::compress(lua_state, None, false)?
   } else {
-    bail!("unknown version {}", version);
+    lz4::block::compress(&[lua_state, lua_state_tail].concat(), None, false)?
   };
+  write::u32(contents, lua_state_lz4.len() as u32);
+  write::bytes(contents, &lua_state_lz4);
+  Ok(())
 }
 
-pub fn read_v16(loadstate: &mut &[u8]) -> Result<HadesSaveV16> {
-  let timestamp = read::u64(loadstate).context("timestamp")?;
-  let location = read_string(loadstate).context("location")?;
-  let runs = read::u32(loadstate).context("runs")?;
-  let active_meta_points = read::u32(loadstate).context("active_meta_points")?;
-  let active_shrine_points = read::u32(loadstate).context("active_shrine_points")?;
-  let god_mode_enabled = read::byte(loadstate).context("god_mode_enabled")? != 0;
-  let hell_mode_enabled = read::byte(loadstate).context("hell_mode_enabled")? != 0;
-
-  let mut lua_keys = Vec::new();
-  let size = read::u32(loadstate).context("lua_keys size")?;
-  for _ in 0..size {
-    let lua_key = read_string(loadstate).context("lua_key")?;
-    lua_keys.push(lua_key);
-  }
-
-  let current_map_name = read_string(loadstate).context("current_map_name")?;
-  let start_next_map = read_string(loadstate).context("start_next_map")?;
-  let lua_state_size = read::u32(loadstate).context("lua_state size")?;
-  let lua_state_lz4 = read::bytes(loadstate, lua_state_size.try_into().unwrap()).context("lua_state bytes")?;
-  
-  let lua_state = lz4::block::decompress(
-    &lua_state_lz4,
-    Some(HadesSaveV16::UNCOMPRESSED_SIZE))?;
-
-  Ok(HadesSaveV16 {
-    timestamp: timestamp,
-    location: location,
-    runs: runs,
-    active_meta_points: active_meta_points,
-    active_shrine_points: active_shrine_points,
-    god_mode_enabled: god_mode_enabled,
-    hell_mode_enabled: hell_mode_enabled,
-    lua_keys: lua_keys,
-    current_map_name: current_map_name,
-    start_next_map: start_next_map,
-    lua_state: lua_state
+// The compressed lua state is always the last thing in the file, so without
+// a known layout it's found by looking for the first offset whose u32 is the
+// length of everything after it.
+fn find_lua_state(loadstate: &[u8]) -> Option<usize> {
+  (0..loadstate.len().saturating_sub(4)).find(|offset| {
+    let mut rest = &loadstate[*offset..];
+    match read::u32(&mut rest) {
+      Ok(size) => size as usize == rest.len(),
+      Err(_) => false
+    }
   })
 }
 
-pub fn read_v17(loadstate: &mut &[u8]) -> Result<HadesSaveV17> {
-  let timestamp = read::u64(loadstate).context("timestamp")?;
-  let location = read_string(loadstate).context("location")?;
-  let padding1 = read::bytes(loadstate, 12).context("padding1")?.to_vec();
-  let runs = read::u32(loadstate).context("runs")?;
-  let god_mode_enabled = read::byte(loadstate).context("god_mode_enabled")? != 0;
-  let hell_mode_enabled = read::byte(loadstate).context("hell_mode_enabled")? != 0;
-
-  let mut lua_keys = Vec::new();
-  let size = read::u32(loadstate).context("lua_keys size")?;
-  for _ in 0..size {
-    let lua_key = read_string(loadstate).context("lua_key")?;
-    lua_keys.push(lua_key);
-  }
-
-  let current_map_name = read_string(loadstate).context("current_map_name")?;
-  let start_next_map = read_string(loadstate).context("start_next_map")?;
-  let lua_state_size = read::u32(loadstate).context("lua_state size")?;
-  let lua_state_lz4 = read::bytes(loadstate, lua_state_size.try_into().unwrap()).context("lua_state bytes")?;
-  
-  let lua_state = lz4::block::decompress(
-    &lua_state_lz4,
-    Some(HadesSaveV16::UNCOMPRESSED_SIZE))?;
-
-  let lua_size = luabins::size(lua_state.as_slice())?;
-  println!(
-    "uncompressed {} luasize {}",
-    lua_state.len(),
-    lua_size);
-
-  Ok(HadesSaveV17 {
-    timestamp: timestamp,
-    location: location,
-    padding1: padding1,
-    runs: runs,
-    god_mode_enabled: god_mode_enabled,
-    hell_mode_enabled: hell_mode_enabled,
-    lua_keys: lua_keys,
-    current_map_name: current_map_name,
-    start_next_map: start_next_map,
-    lua_state: lua_state[0..lua_size].to_vec()
+fn read_raw(loadstate: &mut &[u8], version: u32) -> Result<HadesSaveRaw> {
+  let offset = find_lua_state(loadstate).ok_or(SaveError::UnsupportedVersion(version))?;
+  let header = read::bytes(loadstate, offset)?.to_vec();
+  let (lua_state, lua_state_tail, compressed_size) = read_lua_state(loadstate)?;
+  Ok(HadesSaveRaw {
+    version: version,
+    header: header,
+    lua_state: lua_state,
+    lua_state_tail: lua_state_tail,
+    compressed_size: Some(compressed_size)
   })
 }
 
-fn write_string(contents: &mut Vec<u8>, string: &str) {
-  write::u32(contents, string.len() as u32);
-  let mut str_bytes = string.as_bytes().to_owned();
-  write::bytes(contents, &mut str_bytes);
+/// Compares the checksum stored in a save file against the adler32 of
+/// everything after it.
+pub fn check_checksum(file: &[u8]) -> Result<()> {
+  let mut loadstate = file;
+  read::bytes(&mut loadstate, 4)?;
+  let expected = read::u32(&mut loadstate)?;
+  let actual = adler32(loadstate)?;
+  if actual != expected {
+    return Err(SaveError::ChecksumMismatch { expected: expected, actual: actual });
+  }
+  Ok(())
 }
 
-pub fn write (save: &HadesSave) -> Result<Vec<u8>> {
-  match save {
-    HadesSave::V16(save) => return write_v16(save),
-    HadesSave::V17(save) => return write_v17(save)
-  }
+/// Reads a save file. The checksum stored in the header is compared against
+/// the adler32 of everything after it; pass ignore_checksum to open damaged
+/// saves anyway. Versions without a known layout are read as HadesSave::Raw.
+/// Truncations in the file are reported with their offset into it. Lua
+/// states too large for the game are read anyway; see check_size. Anything
+/// after the luabins blob is kept apart from it; see lua_state_tail.
+pub fn read(loadstate: &mut &[u8], ignore_checksum: bool) -> Result<HadesSave> {
+  let file_size = loadstate.len();
+  read_save(loadstate, ignore_checksum).map_err(|err| match err {
+    SaveError::Truncated { offset: None, needed, available } => {
+      SaveError::Truncated { offset: Some(file_size - available), needed: needed, available: available }
+    },
+    err => err
+  })
 }
 
-pub fn write_v16 (save: &HadesSaveV16) -> Result<Vec<u8>> {
-  let mut contents: Vec<u8> = Vec::new();
-  let mut signature = "SGB1".as_bytes().to_owned();
-  write::bytes(&mut contents, &mut signature);
-  let mut checksum = "TODO".as_bytes().to_owned();
-  write::bytes(&mut contents, &mut checksum);
-  write::u32(&mut contents, 16); // version
-  write::u64(&mut contents, save.timestamp);
-  write_string(&mut contents, &save.location);
-  write::u32(&mut contents, save.runs);
-  write::u32(&mut contents, save.active_meta_points);
-  write::u32(&mut contents, save.active_shrine_points);
-  write::byte(&mut contents, if save.god_mode_enabled {1} else {0});
-  write::byte(&mut contents, if save.hell_mode_enabled {1} else {0});
-
-  // lua keys
-  write::u32(&mut contents, save.lua_keys.len() as u32);
-  for lua_key in save.lua_keys.iter() {
-    write_string(&mut contents, lua_key)
-  }
-
-  write_string(&mut contents, &save.current_map_name);
-  write_string(&mut contents, &save.start_next_map);
-
-  let mut lua_state_lz4 = lz4::block::compress(&save.lua_state, None, false)?;
-  write::u32(&mut contents, lua_state_lz4.len() as u32);
-  write::bytes(&mut contents, &mut lua_state_lz4);
-
-  let checksum_bytes = adler32(&contents[8..])?.to_ne_bytes();
-  contents[4] = checksum_bytes[0];
-  contents[5] = checksum_bytes[1];
-  contents[6] = checksum_bytes[2];
-  contents[7] = checksum_bytes[3];
+fn read_save(loadstate: &mut &[u8], ignore_checksum: bool) -> Result<HadesSave> {
+  let file = *loadstate;
+  let signature = read::bytes(loadstate, 4)?;
+  if signature != "SGB1".as_bytes() {
+    return Err(SaveError::BadSignature);
+  }
+  read::u32(loadstate)?;
+  if !ignore_checksum {
+    check_checksum(file)?;
+  }
+  let version = read::u32(loadstate)?;
+  match version {
+    HadesSaveV16::VERSION => Ok(HadesSave::V16(HadesSaveV16::read_body(loadstate)?)),
+    HadesSaveV17::VERSION => Ok(HadesSave::V17(HadesSaveV17::read_body(loadstate)?)),
+    _ => Ok(HadesSave::Raw(read_raw(loadstate, version)?))
+  }
+}
 
-  Ok(contents)
+/// Maps the header onto another version's layout where the fields correspond,
+/// returning warnings for the fields that were dropped, defaulted or mapped on
+/// a guess. The lua state is carried over as is; see luastate::convert.
+pub fn convert(save: &HadesSave, version: u32) -> Result<(HadesSave, Vec<Warning>)> {
+  match (save, version) {
+    (HadesSave::V16(_), HadesSaveV16::VERSION) | (HadesSave::V17(_), HadesSaveV17::VERSION) => {
+      Ok((save.clone(), Vec::new()))
+    },
+    (HadesSave::V16(data), HadesSaveV17::VERSION) => {
+      Ok((HadesSave::V17(HadesSaveV17 {
+        timestamp: data.timestamp,
+        location: data.location.clone(),
+        unknown0: data.active_meta_points,
+        unknown1: data.active_shrine_points,
+        unknown2: 0,
+        runs: data.runs,
+        god_mode_enabled: data.god_mode_enabled,
+        hell_mode_enabled: data.hell_mode_enabled,
+        lua_keys: data.lua_keys.clone(),
+        current_map_name: data.current_map_name.clone(),
+        start_next_map: data.start_next_map.clone(),
+        lua_state: data.lua_state.clone(),
+        lua_state_tail: data.lua_state_tail.clone(),
+        compressed_size: None
+      }), vec![
+        Warning::FieldGuessed { from: "active_meta_points", to: "unknown0" },
+        Warning::FieldGuessed { from: "active_shrine_points", to: "unknown1" },
+        Warning::FieldDefaulted("unknown2")
+      ]))
+    },
+    (HadesSave::V17(data), HadesSaveV16::VERSION) => {
+      Ok((HadesSave::V16(HadesSaveV16 {
+        timestamp: data.timestamp,
+        location: data.location.clone(),
+        runs: data.runs,
+        active_meta_points: data.unknown0,
+        active_shrine_points: data.unknown1,
+        god_mode_enabled: data.god_mode_enabled,
+        hell_mode_enabled: data.hell_mode_enabled,
+        lua_keys: data.lua_keys.clone(),
+        current_map_name: data.current_map_name.clone(),
+        start_next_map: data.start_next_map.clone(),
+        lua_state: data.lua_state.clone(),
+        lua_state_tail: data.lua_state_tail.clone(),
+        compressed_size: None
+      }), vec![
+        Warning::FieldGuessed { from: "unknown0", to: "active_meta_points" },
+        Warning::FieldGuessed { from: "unknown1", to: "active_shrine_points" },
+        Warning::FieldDropped("unknown2")
+      ]))
+    },
+    (HadesSave::Raw(data), _) => Err(SaveError::UnsupportedVersion(data.version)),
+    _ => Err(SaveError::UnsupportedVersion(version))
+  }
 }
 
+/// Compares the header's lua_keys with the globals actually in the lua state,
+/// returning the keys missing from the header and the keys the state doesn't
+/// have.
+pub fn lua_key_mismatches(save: &HadesSave) -> Result<(Vec<String>, Vec<String>)> {
+  let lua_keys = match save.lua_keys() {
+    Some(lua_keys) => lua_keys,
+    None => return Ok((Vec::new(), Vec::new()))
+  };
+  let state_keys: Vec<String> = luabins::top_level_sizes(save.lua_state())?
+    .into_iter()
+    .filter_map(|(key, _)| key)
+    .collect();
+  let missing = state_keys.iter().filter(|key| !lua_keys.contains(key)).cloned().collect();
+  let extra = lua_keys.iter().filter(|key| !state_keys.contains(key)).cloned().collect();
+  Ok((missing, extra))
+}
 
-pub fn write_v17 (save: &HadesSaveV17) -> Result<Vec<u8>> {
-  let mut contents: Vec<u8> = Vec::new();
-  let mut signature = "SGB1".as_bytes().to_owned();
-  write::bytes(&mut contents, &mut signature);
-  let mut checksum = "TODO".as_bytes().to_owned();
-  write::bytes(&mut contents, &mut checksum);
-  write::u32(&mut contents, 17); // version
-  write::u64(&mut contents, save.timestamp);
-  write_string(&mut contents, &save.location);
-  write::bytes(&mut contents, &mut save.padding1.clone());
-  write::u32(&mut contents, save.runs);
-  write::byte(&mut contents, if save.god_mode_enabled {1} else {0});
-  write::byte(&mut contents, if save.hell_mode_enabled {1} else {0});
+/// Something about a save worth telling the user, which didn't stop it being
+/// saved. The library doesn't print these; callers do.
+#[derive(Debug)]
+pub enum Warning {
+  /// The lua state overflows the game's buffer, and was written anyway
+  /// because force was set.
+  TooLarge(SaveError),
+  /// A global saved in the lua state but missing from lua_keys.
+  NotInLuaKeys(String),
+  /// A lua_keys entry with no global in the lua state.
+  NotSaved(String),
+  /// sync_lua_keys added a global to lua_keys.
+  LuaKeyAdded(String),
+  /// sync_lua_keys removed an entry from lua_keys.
+  LuaKeyRemoved(String),
+  /// convert found no place for a header field in the target version.
+  FieldDropped(&'static str),
+  /// convert set a header field the source version doesn't have to zero.
+  FieldDefaulted(&'static str),
+  /// convert carried a header field over to one that only might hold the
+  /// same thing; see the v17 layout.
+  FieldGuessed { from: &'static str, to: &'static str }
+}
 
-  // lua keys
-  write::u32(&mut contents, save.lua_keys.len() as u32);
-  for lua_key in save.lua_keys.iter() {
-    write_string(&mut contents, lua_key)
+impl fmt::Display for Warning {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    match self {
+      Warning::TooLarge(err) => write!(f, "{}", err),
+      Warning::NotInLuaKeys(key) => write!(f, "{} is saved but not listed in lua_keys", key),
+      Warning::NotSaved(key) => write!(f, "{} is listed in lua_keys but not saved", key),
+      Warning::LuaKeyAdded(key) => write!(f, "lua_keys: adding {}", key),
+      Warning::LuaKeyRemoved(key) => write!(f, "lua_keys: removing {}", key),
+      Warning::FieldDropped(field) => write!(f, "dropping header field {}", field),
+      Warning::FieldDefaulted(field) => write!(f, "header field {} defaulted to 0", field),
+      Warning::FieldGuessed { from, to } => {
+        write!(f, "header field {} copied to {}, which may not hold the same thing", from, to)
+      }
+    }
   }
+}
 
-  write_string(&mut contents, &save.current_map_name);
-  write_string(&mut contents, &save.start_next_map);
+/// Brings lua_keys in line with the lua state, keeping the existing order and
+/// appending new globals at the end. Returns what changed.
+pub fn sync_lua_keys(save: &mut HadesSave) -> Result<Vec<Warning>> {
+  let (missing, extra) = lua_key_mismatches(save)?;
+  let mut changes = Vec::new();
+  if let Some(lua_keys) = save.lua_keys_mut() {
+    lua_keys.retain(|key| !extra.contains(key));
+    changes.extend(extra.into_iter().map(Warning::LuaKeyRemoved));
+    lua_keys.extend(missing.iter().cloned());
+    changes.extend(missing.into_iter().map(Warning::LuaKeyAdded));
+  }
+  Ok(changes)
+}
 
-  let lua_size = luabins::size(&save.lua_state)?;
-  println!("lua_state.len() {} lua_size {}", save.lua_state.len(), lua_size);
+/// Checks that the lua state fits in the buffer the game decompresses it
+/// into, since the game cannot load a save that overflows it.
+pub fn check_size(lua_state: &[u8], limit: i32) -> Result<()> {
+  let limit: usize = limit.try_into().unwrap();
+  if lua_state.len() > limit {
+    let mut largest: Vec<(String, usize)> = luabins::top_level_sizes(lua_state)
+      .unwrap_or_default()
+      .into_iter()
+      .map(|(key, size)| (key.unwrap_or_else(|| "(non-string key)".to_owned()), size))
+      .collect();
+    largest.sort_by_key(|(_, size)| std::cmp::Reverse(*size));
+    return Err(SaveError::StateTooLarge { size: lua_state.len(), limit: limit, largest: largest });
+  }
+  Ok(())
+}
 
-  let mut lua_state_lz4 = lz4::block::compress(&save.lua_state, None, false)?;
+/// Serializes the save, refusing to write one the game can't load unless
+/// force is set. Returns the file along with anything wrong with it.
+pub fn write(save: &HadesSave, force: bool) -> Result<(Vec<u8>, Vec<Warning>)> {
+  let mut warnings = Vec::new();
+  match check_size(save.lua_state(), save.uncompressed_size()) {
+    Err(err) if force => warnings.push(Warning::TooLarge(err)),
+    result => result?
+  }
+  let (missing, extra) = lua_key_mismatches(save)?;
+  warnings.extend(missing.into_iter().map(Warning::NotInLuaKeys));
+  warnings.extend(extra.into_iter().map(Warning::NotSaved));
 
-  write::u32(&mut contents, lua_state_lz4.len() as u32);
-  write::bytes(&mut contents, &mut lua_state_lz4);
+  let mut contents: Vec<u8> = Vec::new();
+  write::bytes(&mut contents, "SGB1".as_bytes());
+  write::bytes(&mut contents, "TODO".as_bytes());
+  write::u32(&mut contents, save.version());
+  match save {
+    HadesSave::V16(save) => save.write_body(&mut contents)?,
+    HadesSave::V17(save) => save.write_body(&mut contents)?,
+    HadesSave::Raw(save) => {
+      write::bytes(&mut contents, &save.header);
+      write_lua_state(&mut contents, &save.lua_state, &save.lua_state_tail)?
+    }
+  }
 
-  let checksum_bytes = adler32(&contents[8..])?.to_ne_bytes();
+  let checksum_bytes = adler32(&contents[8..])?.to_le_bytes();
   contents[4] = checksum_bytes[0];
   contents[5] = checksum_bytes[1];
   contents[6] = checksum_bytes[2];
   contents[7] = checksum_bytes[3];
 
-  Ok(contents)
-}
\ No newline at end of file
+  Ok((contents, warnings))
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+  use super::*;
+  use crate::luabins::LuabinsValue;
+
+  // A v17 save built by hand, with a lua state of two globals: Gold = 1234.5678
+  // and Favor = e. The lz4 block is all literals, which is also what lz4 makes
+  // of a block this short and free of repeats, so writing it back gives the
+  // same bytes.
+  const SAVE: &[u8] = &[
+    b'S', b'G', b'B', b'1',
+    0x78, 0x1e, 0x4e, 0x74, // adler32 of everything after it
+    17, 0, 0, 0, // version
+    0x00, 0xf1, 0x53, 0x65, 0, 0, 0, 0, // timestamp, 1700000000
+    8, 0, 0, 0, b'T', b'a', b'r', b't', b'a', b'r', b'u', b's', // location
+    3, 0, 0, 0, 7, 0, 0, 0, 9, 0, 0, 0, // unknown0..2
+    42, 0, 0, 0, // runs
+    1, 0, // god_mode_enabled, hell_mode_enabled
+    2, 0, 0, 0, 4, 0, 0, 0, b'G', b'o', b'l', b'd', 5, 0, 0, 0, b'F', b'a', b'v', b'o', b'r', // lua_keys
+    11, 0, 0, 0, b'R', b'o', b'o', b'm', b'O', b'p', b'e', b'n', b'i', b'n', b'g', // current_map_name
+    12, 0, 0, 0, b'R', b'o', b'o', b'm', b'S', b'i', b'm', b'p', b'l', b'e', b'0', b'1', // start_next_map
+    49, 0, 0, 0, // lz4 block size
+    0xf0, 32, // 15 + 32 literals
+    1, // one value
+    b'T', 0, 0, 0, 0, 2, 0, 0, 0, // a table of two hash entries
+    b'S', 4, 0, 0, 0, b'G', b'o', b'l', b'd',
+    b'N', 0xad, 0xfa, 0x5c, 0x6d, 0x45, 0x4a, 0x93, 0x40,
+    b'S', 5, 0, 0, 0, b'F', b'a', b'v', b'o', b'r',
+    b'N', 0x69, 0x57, 0x14, 0x8b, 0x0a, 0xbf, 0x05, 0x40
+  ];
+
+  // The same save laid out the way Hades II writes it, with the lua state
+  // followed by zeros (64 here) inside the lz4 block.
+  pub(crate) const PADDED_SAVE: &[u8] = &[
+    b'S', b'G', b'B', b'1',
+    0x36, 0x1f, 0xa9, 0xea, // adler32 of everything after it
+    17, 0, 0, 0, // version
+    0x00, 0xf1, 0x53, 0x65, 0, 0, 0, 0, // timestamp, 1700000000
+    8, 0, 0, 0, b'T', b'a', b'r', b't', b'a', b'r', b'u', b's', // location
+    3, 0, 0, 0, 7, 0, 0, 0, 9, 0, 0, 0, // unknown0..2
+    42, 0, 0, 0, // runs
+    1, 0, // god_mode_enabled, hell_mode_enabled
+    2, 0, 0, 0, 4, 0, 0, 0, b'G', b'o', b'l', b'd', 5, 0, 0, 0, b'F', b'a', b'v', b'o', b'r', // lua_keys
+    11, 0, 0, 0, b'R', b'o', b'o', b'm', b'O', b'p', b'e', b'n', b'i', b'n', b'g', // current_map_name
+    12, 0, 0, 0, b'R', b'o', b'o', b'm', b'S', b'i', b'm', b'p', b'l', b'e', b'0', b'1', // start_next_map
+    61, 0, 0, 0, // lz4 block size
+    0xf0, 32, // 15 + 32 literals, then a 4 byte match
+    1,
+    b'T', 0, 0, 0, 0, 2, 0, 0, 0,
+    b'S', 4, 0, 0, 0, b'G', b'o', b'l', b'd',
+    b'N', 0xad, 0xfa, 0x5c, 0x6d, 0x45, 0x4a, 0x93, 0x40,
+    b'S', 5, 0, 0, 0, b'F', b'a', b'v', b'o', b'r',
+    b'N', 0x69, 0x57, 0x14, 0x8b, 0x0a, 0xbf, 0x05, 0x40,
+    45, 0, // 4 zeros, copied from the table's array size
+    0x0f, 2, 0, 36, // no literals, 15 + 36 + 4 more zeros
+    0x50, 0, 0, 0, 0, 0 // the last 5 zeros, as literals
+  ];
+
+  #[test]
+  fn reads_a_known_save() {
+    check_checksum(SAVE).unwrap();
+    assert_eq!(adler32(&SAVE[8..]).unwrap(), 0x744e1e78);
+
+    let save = read(&mut &SAVE[..], false).unwrap();
+    assert_eq!(save.compressed_size(), Some(49));
+    let header = match &save {
+      HadesSave::V17(header) => header,
+      _ => panic!("read as version {}", save.version())
+    };
+    assert_eq!(header.timestamp, 1700000000);
+    assert_eq!(header.location, "Tartarus");
+    assert_eq!((header.unknown0, header.unknown1, header.unknown2), (3, 7, 9));
+    assert_eq!(header.runs, 42);
+    assert!(header.god_mode_enabled);
+    assert!(!header.hell_mode_enabled);
+    assert_eq!(header.lua_keys, ["Gold", "Favor"]);
+    assert_eq!(header.current_map_name, "RoomOpening");
+    assert_eq!(header.start_next_map, "RoomSimple01");
+
+    let values = luabins::decode(&mut save.lua_state()).unwrap();
+    let globals = match values.as_slice() {
+      [LuabinsValue::Table(globals)] => globals,
+      _ => panic!("unexpected values {:?}", values)
+    };
+    assert_eq!((globals.array_size, globals.hash_size), (0, 2));
+    let numbers: Vec<(&[u8], f64)> = globals.entries.iter()
+      .map(|entry| match entry {
+        (LuabinsValue::String(key), LuabinsValue::Number(n)) => (key.as_ref(), *n),
+        _ => panic!("unexpected entry {:?}", entry)
+      })
+      .collect();
+    assert_eq!(numbers, [(&b"Gold"[..], 1234.5678), (&b"Favor"[..], std::f64::consts::E)]);
+  }
+
+  #[test]
+  fn writes_a_known_save_back_unchanged() {
+    let save = read(&mut &SAVE[..], false).unwrap();
+    let (file, warnings) = write(&save, false).unwrap();
+    assert!(warnings.is_empty(), "{:?}", warnings);
+    assert_eq!(file, SAVE);
+  }
+
+  #[test]
+  fn refuses_blocks_claiming_a_huge_state() {
+    // One sequence with no literals and a match length of ~40 MB.
+    let mut block = vec![0x0f, 1, 0];
+    block.resize(block.len() + MAX_DECOMPRESSED_SIZE / 255 + 1, 0xff);
+    block.push(0);
+    block.push(0);
+    match decompress(&block) {
+      Err(SaveError::Decompression(_)) => {},
+      Err(err) => panic!("unexpected error {}", err),
+      Ok(state) => panic!("decompressed {} bytes", state.len())
+    }
+  }
+
+  #[test]
+  fn keeps_the_padding_after_the_lua_state() {
+    let mut save = read(&mut &PADDED_SAVE[..], false).unwrap();
+    assert_eq!(save.lua_state(), read(&mut &SAVE[..], false).unwrap().lua_state());
+    assert_eq!(save.lua_state_tail(), &[0; 64][..]);
+    assert_eq!(write(&save, false).unwrap().0, PADDED_SAVE);
+
+    // Re-encoding the state unchanged, as every edit path does, keeps it.
+    let values = luabins::decode(&mut save.lua_state()).unwrap();
+    let mut lua_state = Vec::new();
+    luabins::encode(&mut lua_state, &values).unwrap();
+    save.set_lua_state(lua_state);
+    assert_eq!(write(&save, false).unwrap().0, PADDED_SAVE);
+
+    // The padding gives way to a state that only just fits.
+    save.set_lua_state(vec![0; HadesSaveV17::UNCOMPRESSED_SIZE as usize - 10]);
+    assert_eq!(save.lua_state_tail().len(), 10);
+  }
+}
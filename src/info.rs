@@ -0,0 +1,98 @@
+use anyhow::Result;
+use hadessave::{hadesfile, read_file, SaveError};
+use serde_json::{json, Value};
+use std::path::Path;
+
+// Header fields worth showing, with their labels; saves with an unknown
+// layout have none of them.
+const FIELDS: &[(&str, &str)] = &[
+    ("location", "location"),
+    ("runs", "runs"),
+    ("god_mode_enabled", "god mode"),
+    ("hell_mode_enabled", "hell mode"),
+    ("current_map_name", "current map"),
+    ("start_next_map", "next map"),
+];
+
+pub fn info(path: &Path, format: &str) -> Result<()> {
+    let file = read_file(path)?;
+    let savedata = hadesfile::read(&mut file.as_slice(), true)?;
+    let header = savedata.header_json();
+    let checksum = match hadesfile::check_checksum(&file) {
+        Ok(()) => Ok(()),
+        Err(SaveError::ChecksumMismatch { expected, actual }) => Err((expected, actual)),
+        Err(err) => return Err(err.into())
+    };
+
+    let mut summary = json!({
+        "version": savedata.version(),
+        "compressed_size": savedata.compressed_size(),
+        "uncompressed_size": savedata.lua_state().len(),
+        "tail_size": savedata.lua_state_tail().len(),
+        "uncompressed_limit": savedata.uncompressed_size(),
+        "checksum_valid": checksum.is_ok(),
+        "lua_keys": savedata.lua_keys()
+    });
+    if let Some(timestamp) = header["timestamp"].as_u64() {
+        summary["timestamp"] = json!(timestamp);
+        summary["date"] = json!(format_date(timestamp));
+    }
+    for (field, _) in FIELDS {
+        if !header[field].is_null() {
+            summary[field] = header[field].clone();
+        }
+    }
+
+    if format == "json" {
+        println!("{}", summary);
+        return Ok(());
+    }
+
+    println!("{:18} {}", "version", savedata.version());
+    if let Some(date) = summary["date"].as_str() {
+        println!("{:18} {}", "date", date);
+    }
+    for (field, label) in FIELDS {
+        match &summary[field] {
+            Value::Null => {},
+            Value::Bool(true) => println!("{:18} on", label),
+            Value::Bool(false) => println!("{:18} off", label),
+            Value::String(s) => println!("{:18} {}", label, s),
+            value => println!("{:18} {}", label, value)
+        }
+    }
+    match summary["compressed_size"].as_u64() {
+        Some(size) => println!("{:18} {} bytes", "compressed size", size),
+        None => println!("{:18} unknown", "compressed size")
+    }
+    println!("{:18} {} bytes (limit {})", "uncompressed size", savedata.lua_state().len(), savedata.uncompressed_size());
+    if !savedata.lua_state_tail().is_empty() {
+        println!("{:18} {} bytes after the lua state", "padding", savedata.lua_state_tail().len());
+    }
+    match checksum {
+        Ok(()) => println!("{:18} ok", "checksum"),
+        Err((expected, actual)) => println!("{:18} mismatch (expected {:08x}, actual {:08x})", "checksum", expected, actual)
+    }
+    if let Some(lua_keys) = savedata.lua_keys() {
+        println!("{:18} {}", "lua_keys", lua_keys.join(", "));
+    }
+    Ok(())
+}
+
+// The timestamp is seconds since the unix epoch; formatted as a UTC date.
+fn format_date(timestamp: u64) -> String {
+    let days = (timestamp / 86400) as i64;
+    let seconds = timestamp % 86400;
+    // Civil date from days since 1970-01-01, in 400 year eras of 146097 days
+    // starting on March 1st.
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let day_of_era = z.rem_euclid(146097);
+    let year_of_era = (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let month_index = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * month_index + 2) / 5 + 1;
+    let month = if month_index < 10 { month_index + 3 } else { month_index - 9 };
+    let year = year_of_era + era * 400 + if month <= 2 { 1 } else { 0 };
+    format!("{:04}-{:02}-{:02} {:02}:{:02}:{:02} UTC", year, month, day, seconds / 3600, seconds / 60 % 60, seconds % 60)
+}
@@ -0,0 +1,57 @@
+//! Reading, editing and writing Hades save files.
+//!
+//! [`open`] reads a save into a [`HadesSave`]. Its header fields are
+//! available directly or as json through [`HadesSave::header_json`], and its
+//! lua state decodes with [`luabins::decode`] into plain [`LuabinsValue`]s.
+//! Edit those and [`luabins::encode`] them back with
+//! [`HadesSave::set_lua_state`], or load the whole save into a lua
+//! interpreter with [`luastate::load`] and take it back out with
+//! [`luastate::save`]. [`save`] writes the result.
+//!
+//! Everything that can fail returns a [`SaveError`].
+
+// Struct literals spell out field: value even when the names match.
+#![allow(clippy::redundant_field_names)]
+
+pub mod error;
+pub mod hadesfile;
+pub mod luabins;
+pub mod luadiff;
+pub mod luajson;
+pub mod luapatch;
+pub mod luastate;
+pub mod luavalue;
+mod read;
+mod write;
+
+pub use error::SaveError;
+pub use hadesfile::{HadesSave, Warning};
+pub use luabins::{LuabinsTable, LuabinsValue};
+
+use error::Result;
+use std::fs;
+use std::path::Path;
+
+const BYTE_ORDER_MARK: &[u8] = "\u{feff}".as_bytes();
+
+/// Reads a file, dropping the utf-8 byte order mark some editors add.
+pub fn read_file<P: AsRef<Path>>(path: P) -> Result<Vec<u8>> {
+  let mut file = fs::read(path)?;
+  if file.starts_with(BYTE_ORDER_MARK) {
+    file.drain(..BYTE_ORDER_MARK.len());
+  }
+  Ok(file)
+}
+
+/// Reads the save file at path; see [`hadesfile::read()`].
+pub fn open<P: AsRef<Path>>(path: P, ignore_checksum: bool) -> Result<HadesSave> {
+  let file = read_file(path)?;
+  hadesfile::read(&mut file.as_slice(), ignore_checksum)
+}
+
+/// Writes the save to path; see [`hadesfile::write()`].
+pub fn save<P: AsRef<Path>>(path: P, save: &HadesSave, force: bool) -> Result<Vec<Warning>> {
+  let (outfile, warnings) = hadesfile::write(save, force)?;
+  fs::write(path, outfile)?;
+  Ok(warnings)
+}
@@ -1,10 +1,13 @@
+//! The luabins format the game serializes its lua globals with.
+
+use crate::error::{Result, SaveError};
 use crate::read;
 use crate::write;
 
-use anyhow::{anyhow, Context, Result};
-use rlua::Lua;
+use std::borrow::Cow;
+use std::collections::HashSet;
 use std::convert::TryInto;
-use rlua::Value;
+use std::fmt;
 
 const LUABINS_CNIL: u8 = 0x2D;
 const LUABINS_CFALSE: u8 = 0x30;
@@ -13,155 +16,504 @@ const LUABINS_CNUMBER: u8 = 0x4E;
 const LUABINS_CSTRING: u8 = 0x53 ;
 const LUABINS_CTABLE: u8 = 0x54;
 
-fn load_number<'lua>(loadstate: &mut &[u8]) -> Result<rlua::Value<'lua>> {
-  let float = read::f64(loadstate)?;
-  //println!("number {}", float);
-  if float.fract() == 0.0 {
-    Ok(Value::Integer(float.trunc() as i64))
-  } else {
-    Ok(Value::Number(float))
+/// A value as stored in a luabins blob, independent of any lua interpreter.
+/// See luavalue for converting to and from rlua values. Decoded strings
+/// borrow from the blob rather than copying out of it.
+#[derive(Clone, Debug)]
+pub enum LuabinsValue<'a> {
+  Nil,
+  Boolean(bool),
+  Number(f64),
+  String(Cow<'a, [u8]>),
+  Table(LuabinsTable<'a>)
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct LuabinsTable<'a> {
+  pub array_size: i32,
+  pub hash_size: i32,
+  /// In the order they appear in the blob.
+  pub entries: Vec<(LuabinsValue<'a>, LuabinsValue<'a>)>
+}
+
+impl LuabinsTable<'_> {
+  /// Recomputes the sizes after entries were added or removed; see
+  /// sizes_for_keys.
+  pub fn update_sizes(&mut self) {
+    let (array_size, hash_size) = sizes_for_keys(self.entries.iter().map(|(key, _)| key.as_integer()));
+    self.array_size = array_size;
+    self.hash_size = hash_size;
   }
 }
 
-fn load_string<'lua>(loadstate: &mut &[u8], context: rlua::Context<'lua>) -> Result<rlua::String<'lua>> {
-  let len = read::u32(loadstate).context("string size")?;
-  let str_bytes = read::bytes(loadstate, len.try_into().unwrap()).context("string")?;
-  //println!("string {} {:?}", len, std::str::from_utf8(str_bytes));
-  context.create_string(str_bytes).map_err(anyhow::Error::new)
+/// The array and hash sizes for a table with these keys, each given as the
+/// integer it is, if it is one. The array part is 1..n, up to the first
+/// missing integer key.
+pub fn sizes_for_keys<I: IntoIterator<Item = Option<i64>>>(keys: I) -> (i32, i32) {
+  let mut total_size = 0;
+  let integer_keys: HashSet<i64> = keys.into_iter()
+    .inspect(|_| total_size += 1)
+    .flatten()
+    .collect();
+  let mut array_size = 0;
+  while integer_keys.contains(&(array_size as i64 + 1)) {
+    array_size += 1;
+  }
+  (array_size, total_size - array_size)
 }
 
-fn load_table<'lua>(loadstate: &mut &[u8], context: rlua::Context<'lua>) -> Result<rlua::Table<'lua>> {
-  let array_size = read::i32(loadstate).context("array_size")?;
-  let hash_size = read::i32(loadstate).context("hash_size")?;
-  let total_size = array_size + hash_size;
-  let table: rlua::Table<'lua> = context.create_table().context("create_table")?;
+// Numbers compare by their bits, so NaN equals itself and -0.0 doesn't
+// equal 0.0; two values are equal exactly when they encode the same.
+impl PartialEq for LuabinsValue<'_> {
+  fn eq(&self, other: &Self) -> bool {
+    match (self, other) {
+      (LuabinsValue::Nil, LuabinsValue::Nil) => true,
+      (LuabinsValue::Boolean(a), LuabinsValue::Boolean(b)) => a == b,
+      (LuabinsValue::Number(a), LuabinsValue::Number(b)) => a.to_bits() == b.to_bits(),
+      (LuabinsValue::String(a), LuabinsValue::String(b)) => a == b,
+      (LuabinsValue::Table(a), LuabinsValue::Table(b)) => a == b,
+      _ => false
+    }
+  }
+}
 
-  //println!("table arr {} hash {}", array_size, hash_size);
-  for _ in 0..total_size {
-    let key = load_value(loadstate, context).context("key")?;
-    let value = load_value(loadstate, context).context("value")?;
-    table.set(key, value).context("table.set")?;
+impl LuabinsValue<'_> {
+  /// The number as an integer, if it converts to one and back bit-for-bit;
+  /// -0.0, NaN, infinities and anything out of i64 range don't.
+  pub fn as_integer(&self) -> Option<i64> {
+    match self {
+      // The cast saturates, and i64::MAX rounds to 2^63 as a float, so the
+      // range has to be checked first.
+      LuabinsValue::Number(float) if *float >= -9223372036854775808.0 && *float < 9223372036854775808.0 => {
+        let integer = *float as i64;
+        if (integer as f64).to_bits() == float.to_bits() {
+          Some(integer)
+        } else {
+          None
+        }
+      },
+      _ => None
+    }
   }
-  Ok(table)
 }
 
-fn load_value<'a>(loadstate: &mut &[u8], context: rlua::Context<'a>) -> Result<Value<'a>> {
-  let tbyte = read::byte(loadstate).context("type")?;
-  //println!("type {}", tbyte);
-  match tbyte {
-    LUABINS_CNIL => Ok(Value::Nil),
-    LUABINS_CFALSE => Ok(Value::Boolean(false)),   
-    LUABINS_CTRUE => Ok(Value::Boolean(true)),   
-    LUABINS_CNUMBER => Ok(load_number(loadstate).context("cnumber")?),
-    LUABINS_CSTRING => Ok(Value::String(load_string(loadstate, context).context("cstring")?)),
-    LUABINS_CTABLE => Ok(Value::Table(load_table(loadstate, context).context("ctable")?)),
-    _ => Err(anyhow!("unknown type {}", tbyte))
+/// Limits on what a blob may contain. Tables nested deeper than MAX_DEPTH
+/// are refused rather than risking a stack overflow on corrupt data; the top
+/// level count is a single byte.
+pub const MAX_DEPTH: usize = 256;
+pub const MAX_VALUES: usize = 255;
+
+#[derive(Debug)]
+pub enum LuabinsError {
+  Truncated { needed: usize, available: usize },
+  TooManyValues(usize),
+  TooDeep,
+  BadTableSize { array_size: i32, hash_size: i32 },
+  UnknownType(u8)
+}
+
+impl fmt::Display for LuabinsError {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    match self {
+      LuabinsError::Truncated { needed, available } => write!(f, "not enough data (needed {} bytes, {} available)", needed, available),
+      LuabinsError::TooManyValues(count) => write!(f, "too many top-level values ({}, at most {})", count, MAX_VALUES),
+      LuabinsError::TooDeep => write!(f, "tables nested more than {} deep", MAX_DEPTH),
+      LuabinsError::BadTableSize { array_size, hash_size } => write!(f, "bad table size (array {}, hash {})", array_size, hash_size),
+      LuabinsError::UnknownType(tbyte) => write!(f, "unknown type {}", tbyte)
+    }
   }
 }
 
-pub fn load<'lua>(loadstate: &mut &[u8], context: rlua::Context<'lua>) -> Result<Vec<Value<'lua>>> {
-    println!("load size {} [{} {}]", loadstate.len(), loadstate.first().unwrap(), loadstate.last().unwrap());
-    let num_items = read::byte(loadstate).context("num_items")?;
-    //println!("load {}", num_items);
-    let mut vec = Vec::new();
-    for _ in 0..num_items {
-        let value = load_value(loadstate, context).context("load")?;
-        vec.push(value);
+impl std::error::Error for LuabinsError {}
+
+// Reads a table's array and hash sizes, checking they're sane for the data
+// that's left: every entry takes at least two bytes.
+fn read_table_sizes(loadstate: &mut &[u8]) -> std::result::Result<(i32, i32), LuabinsError> {
+  let array_size = read::i32(loadstate)?;
+  let hash_size = read::i32(loadstate)?;
+  let bad_size = LuabinsError::BadTableSize { array_size: array_size, hash_size: hash_size };
+  if array_size < 0 || hash_size < 0 {
+    return Err(bad_size);
+  }
+  match array_size.checked_add(hash_size) {
+    Some(total_size) if (total_size as usize) * 2 <= loadstate.len() => Ok((array_size, hash_size)),
+    _ => Err(bad_size)
+  }
+}
+
+/// Formats a path of table keys like `GameState.Resources[3]`.
+pub fn path_string(path: &[LuabinsValue]) -> String {
+  let mut path_string = String::new();
+  for segment in path {
+    match segment {
+      LuabinsValue::String(s) => {
+        if !path_string.is_empty() {
+          path_string.push('.');
+        }
+        path_string.push_str(&String::from_utf8_lossy(s));
+      },
+      LuabinsValue::Number(n) => match segment.as_integer() {
+        Some(i) => path_string.push_str(&format!("[{}]", i)),
+        None => path_string.push_str(&format!("[{}]", n))
+      },
+      LuabinsValue::Boolean(b) => path_string.push_str(&format!("[{}]", b)),
+      LuabinsValue::Nil => path_string.push_str("[nil]"),
+      LuabinsValue::Table(_) => path_string.push_str("[table]")
     }
-    println!("load remaining {} all0 {}",
-      loadstate.len(),
-      loadstate.iter().all(|x| x == &b'\0'));
-    Ok(vec)
+  }
+  path_string
 }
 
-pub fn size(loadstate: &[u8]) -> Result<usize> {
-  Lua::new().context(|context| -> Result<usize> {
-    let ugh = loadstate.to_owned();
-    let copy = &mut ugh.as_slice();
-    let num_items = read::byte(copy).context("num_items")?;
-    for _ in 0..num_items {
-        load_value(copy, context).context("load")?;
+/// Receives every value in a blob as it's read, with its byte offset and the
+/// path of table keys leading to it. Top-level values have an empty path.
+/// Errors the visitor returns end the walk and are passed on as they are.
+pub trait Visitor<'a> {
+  // Anything but a table.
+  fn scalar(&mut self, offset: usize, path: &[LuabinsValue<'a>], value: LuabinsValue<'a>) -> Result<()>;
+  fn table_start(&mut self, offset: usize, path: &[LuabinsValue<'a>], array_size: i32, hash_size: i32) -> Result<()>;
+  fn table_end(&mut self, path: &[LuabinsValue<'a>]) -> Result<()>;
+}
+
+struct Walker<'a, V: Visitor<'a>> {
+  blob: &'a [u8],
+  rest: &'a [u8],
+  path: Vec<LuabinsValue<'a>>,
+  visitor: V
+}
+
+impl<'a, V: Visitor<'a>> Walker<'a, V> {
+  fn offset(&self) -> usize {
+    self.blob.len() - self.rest.len()
+  }
+
+  // Locates an error at the value starting at offset.
+  fn locate(&self, offset: usize, err: LuabinsError) -> SaveError {
+    SaveError::Luabins {
+      offset: offset,
+      path: path_string(&self.path),
+      error: err
     }
+  }
 
-    let size = loadstate.len() - copy.len();
-    println!("init {} rem {} size {}",
-      loadstate.len(),
-      copy.len(),
-      size);
+  fn table_sizes(&mut self) -> std::result::Result<(i32, i32), LuabinsError> {
+    if self.path.len() >= MAX_DEPTH {
+      return Err(LuabinsError::TooDeep);
+    }
+    read::byte(&mut self.rest)?;
+    read_table_sizes(&mut self.rest)
+  }
 
-    Ok(size)
-  })
+  fn value(&mut self) -> Result<()> {
+    let offset = self.offset();
+    if self.rest.first() != Some(&LUABINS_CTABLE) {
+      let value = decode_value(&mut self.rest, self.path.len()).map_err(|err| self.locate(offset, err))?;
+      return self.visitor.scalar(offset, &self.path, value);
+    }
+    let (array_size, hash_size) = self.table_sizes().map_err(|err| self.locate(offset, err))?;
+    self.visitor.table_start(offset, &self.path, array_size, hash_size)?;
+    for _ in 0..(array_size + hash_size) {
+      let key_offset = self.offset();
+      let key = decode_value(&mut self.rest, self.path.len() + 1).map_err(|err| self.locate(key_offset, err))?;
+      self.path.push(key);
+      self.value()?;
+      self.path.pop();
+    }
+    self.visitor.table_end(&self.path)
+  }
+}
+
+/// Walks the blob, passing each value to the visitor. Errors are
+/// SaveError::Luabins, saying where in the blob things went wrong.
+pub fn walk<'a, V: Visitor<'a>>(loadstate: &mut &'a [u8], visitor: V) -> Result<V> {
+  let mut walker = Walker { blob: loadstate, rest: loadstate, path: Vec::new(), visitor: visitor };
+  let num_items = read::byte(&mut walker.rest).map_err(|err| walker.locate(0, err.into()))?;
+  for _ in 0..num_items {
+    walker.value()?;
+  }
+  *loadstate = walker.rest;
+  Ok(walker.visitor)
 }
 
-fn save_string(savestate: &mut Vec<u8>, string: rlua::String) {
-  let mut str_bytes = string.as_bytes().to_owned();
-  write::u32(savestate, str_bytes.len() as u32);
-  write::bytes(savestate, &mut str_bytes)
+// Builds the value tree from a walk.
+struct TreeBuilder<'a> {
+  values: Vec<LuabinsValue<'a>>,
+  tables: Vec<LuabinsTable<'a>>
 }
 
-fn save_table(savestate: &mut Vec<u8>, table: rlua::Table) -> Result<()> {
-  // If there are holes punched in the "array section", len
-  // will overreport the size. Luabins handles this by limiting
-  // array_size to the total_size.
-  let total_size = table.clone().pairs::<Value, Value>().count() as i32;
-  let array_size = std::cmp::min(total_size, table.len()? as i32);
-  write::i32(savestate, array_size);
-  let hash_size = std::cmp::max(0, total_size - array_size);
-  write::i32(savestate, hash_size);
-  for pair in table.pairs::<Value, Value>() {
-    let (key, value) = pair?;
-    save_value(savestate, key)?;
-    save_value(savestate, value)?;
+impl<'a> TreeBuilder<'a> {
+  fn add(&mut self, path: &[LuabinsValue<'a>], value: LuabinsValue<'a>) {
+    match (self.tables.last_mut(), path.last()) {
+      (Some(table), Some(key)) => table.entries.push((key.clone(), value)),
+      _ => self.values.push(value)
+    }
   }
-  Ok(())
 }
 
-fn save_value(savestate: &mut Vec<u8>, value: Value) -> Result<()> {
-  match value {
-    Value::Nil => {
-      write::byte(savestate, LUABINS_CNIL);
-      Ok(())
+impl<'a> Visitor<'a> for TreeBuilder<'a> {
+  fn scalar(&mut self, _offset: usize, path: &[LuabinsValue<'a>], value: LuabinsValue<'a>) -> Result<()> {
+    self.add(path, value);
+    Ok(())
+  }
+
+  fn table_start(&mut self, _offset: usize, _path: &[LuabinsValue<'a>], array_size: i32, hash_size: i32) -> Result<()> {
+    self.tables.push(LuabinsTable {
+      array_size: array_size,
+      hash_size: hash_size,
+      entries: Vec::new()
+    });
+    Ok(())
+  }
+
+  fn table_end(&mut self, path: &[LuabinsValue<'a>]) -> Result<()> {
+    let table = self.tables.pop().unwrap();
+    self.add(path, LuabinsValue::Table(table));
+    Ok(())
+  }
+}
+
+// Plain recursive decoding, used for table keys.
+fn decode_value<'a>(loadstate: &mut &'a [u8], depth: usize) -> std::result::Result<LuabinsValue<'a>, LuabinsError> {
+  let tbyte = read::byte(loadstate)?;
+  match tbyte {
+    LUABINS_CNIL => Ok(LuabinsValue::Nil),
+    LUABINS_CFALSE => Ok(LuabinsValue::Boolean(false)),
+    LUABINS_CTRUE => Ok(LuabinsValue::Boolean(true)),
+    LUABINS_CNUMBER => Ok(LuabinsValue::Number(read::f64(loadstate)?)),
+    LUABINS_CSTRING => {
+      let len = read::u32(loadstate)?;
+      let str_bytes = read::bytes(loadstate, len.try_into().unwrap())?;
+      Ok(LuabinsValue::String(Cow::Borrowed(str_bytes)))
     },
-    Value::Boolean(boolean_value) => {
-      if boolean_value {
-        write::byte(savestate, LUABINS_CTRUE)
-      } else {
-        write::byte(savestate, LUABINS_CFALSE)
+    LUABINS_CTABLE => {
+      if depth >= MAX_DEPTH {
+        return Err(LuabinsError::TooDeep);
       }
-      Ok(())
+      let (array_size, hash_size) = read_table_sizes(loadstate)?;
+      let mut entries = Vec::new();
+      for _ in 0..(array_size + hash_size) {
+        let key = decode_value(loadstate, depth + 1)?;
+        let value = decode_value(loadstate, depth + 1)?;
+        entries.push((key, value));
+      }
+      Ok(LuabinsValue::Table(LuabinsTable {
+        array_size: array_size,
+        hash_size: hash_size,
+        entries: entries
+      }))
     },
-    Value::Integer(integer_value) => {
-      write::byte(savestate, LUABINS_CNUMBER);
-      write::f64(savestate, integer_value as f64);
+    _ => Err(LuabinsError::UnknownType(tbyte))
+  }
+}
+
+/// Decodes the whole blob in one pass. Strings in the result borrow from it.
+pub fn decode<'a>(loadstate: &mut &'a [u8]) -> Result<Vec<LuabinsValue<'a>>> {
+  let builder = walk(loadstate, TreeBuilder { values: Vec::new(), tables: Vec::new() })?;
+  Ok(builder.values)
+}
+
+fn skip_value(loadstate: &mut &[u8], depth: usize) -> std::result::Result<(), LuabinsError> {
+  let tbyte = read::byte(loadstate)?;
+  match tbyte {
+    LUABINS_CNIL | LUABINS_CFALSE | LUABINS_CTRUE => Ok(()),
+    LUABINS_CNUMBER => read::f64(loadstate).map(|_| ()).map_err(LuabinsError::from),
+    LUABINS_CSTRING => {
+      let len = read::u32(loadstate)?;
+      read::bytes(loadstate, len.try_into().unwrap()).map(|_| ()).map_err(LuabinsError::from)
+    },
+    LUABINS_CTABLE => {
+      if depth >= MAX_DEPTH {
+        return Err(LuabinsError::TooDeep);
+      }
+      let (array_size, hash_size) = read_table_sizes(loadstate)?;
+      for _ in 0..(array_size + hash_size) {
+        skip_value(loadstate, depth + 1)?;
+        skip_value(loadstate, depth + 1)?;
+      }
       Ok(())
     },
-    Value::Number(number_value) => {
+    _ => Err(LuabinsError::UnknownType(tbyte))
+  }
+}
+
+/// The length of the blob at the start of loadstate, which is how much of it
+/// decode reads; anything after that isn't part of it.
+pub fn size(loadstate: &[u8]) -> Result<usize> {
+  let mut rest = loadstate;
+  let mut offset = 0;
+  let mut skip = |rest: &mut &[u8]| -> std::result::Result<(), LuabinsError> {
+    let num_items = read::byte(rest)?;
+    for _ in 0..num_items {
+      offset = loadstate.len() - rest.len();
+      skip_value(rest, 0)?;
+    }
+    Ok(())
+  };
+  skip(&mut rest).map_err(|err| SaveError::Luabins { offset: offset, path: String::new(), error: err })?;
+  Ok(loadstate.len() - rest.len())
+}
+
+/// Serialized size of every entry in the top-level tables, in the order they
+/// were written. Entries are named by their key, or None if it isn't a
+/// string; those aren't globals.
+pub fn top_level_sizes(loadstate: &[u8]) -> Result<Vec<(Option<String>, usize)>> {
+  let mut rest = loadstate;
+  let mut sizes = Vec::new();
+  let mut offset = 0;
+  let mut sizes_from = |rest: &mut &[u8]| -> std::result::Result<(), LuabinsError> {
+    let num_items = read::byte(rest)?;
+    for _ in 0..num_items {
+      offset = loadstate.len() - rest.len();
+      if rest.first() != Some(&LUABINS_CTABLE) {
+        skip_value(rest, 0)?;
+        continue;
+      }
+      read::byte(rest)?;
+      let (array_size, hash_size) = read_table_sizes(rest)?;
+      for _ in 0..(array_size + hash_size) {
+        offset = loadstate.len() - rest.len();
+        let key = if rest.first() == Some(&LUABINS_CSTRING) {
+          let mut key_bytes = &rest[1..];
+          let len = read::u32(&mut key_bytes)?;
+          let name = read::bytes(&mut key_bytes, len.try_into().unwrap())?;
+          Some(String::from_utf8_lossy(name).into_owned())
+        } else {
+          None
+        };
+        skip_value(rest, 1)?;
+        skip_value(rest, 1)?;
+        sizes.push((key, loadstate.len() - offset - rest.len()));
+      }
+    }
+    Ok(())
+  };
+  sizes_from(&mut rest).map_err(|err| SaveError::Luabins { offset: offset, path: String::new(), error: err })?;
+  Ok(sizes)
+}
+
+fn encode_value(savestate: &mut Vec<u8>, value: &LuabinsValue) {
+  match value {
+    LuabinsValue::Nil => write::byte(savestate, LUABINS_CNIL),
+    LuabinsValue::Boolean(true) => write::byte(savestate, LUABINS_CTRUE),
+    LuabinsValue::Boolean(false) => write::byte(savestate, LUABINS_CFALSE),
+    LuabinsValue::Number(number_value) => {
       write::byte(savestate, LUABINS_CNUMBER);
-      write::f64(savestate, number_value);
-      Ok(())
+      write::f64(savestate, *number_value);
     },
-    Value::String(string_value) => {
+    LuabinsValue::String(string_value) => {
       write::byte(savestate, LUABINS_CSTRING);
-      save_string(savestate, string_value);
-      Ok(())
+      write::u32(savestate, string_value.len() as u32);
+      write::bytes(savestate, string_value);
     },
-    Value::Table(table_value) => {
+    LuabinsValue::Table(table_value) => {
       write::byte(savestate, LUABINS_CTABLE);
-      save_table(savestate, table_value)
-    },
-    // Skip the esoteric types; they don't appear in Hades saves.
-    Value::Error(_) => Ok(()),
-    Value::Function(_) => Ok(()),
-    Value::LightUserData(_) => Ok(()),
-    Value::UserData(_) => Ok(()),
-    Value::Thread(_) => Ok(()),
+      write::i32(savestate, table_value.array_size);
+      write::i32(savestate, table_value.hash_size);
+      for (key, value) in table_value.entries.iter() {
+        encode_value(savestate, key);
+        encode_value(savestate, value);
+      }
+    }
   }
 }
 
-pub fn save<'lua>(savestate: &mut Vec<u8>, values: Vec<Value>) -> Result<()> {
+/// Appends the values to savestate as a blob decode can read back.
+pub fn encode(savestate: &mut Vec<u8>, values: &[LuabinsValue]) -> Result<()> {
+  if values.len() > MAX_VALUES {
+    return Err(SaveError::Unencodable(LuabinsError::TooManyValues(values.len())));
+  }
   write::byte(savestate, values.len() as u8);
-  for value in values.into_iter() {
-    save_value(savestate, value)?;
+  for value in values.iter() {
+    encode_value(savestate, value);
   }
   Ok(())
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::luavalue;
+
+  // Numbers that don't survive a naive f64 <-> i64 <-> json trip.
+  fn edge_numbers() -> Vec<f64> {
+    vec![
+      -0.0,
+      f64::NAN,
+      f64::from_bits(0x7ff8000000000001),
+      f64::from_bits(0xfff0000000000123),
+      f64::from_bits(0x7ff4000000000000),
+      f64::INFINITY,
+      f64::NEG_INFINITY,
+      9223372036854775808.0,
+      -9223372036854775808.0,
+      -9223372036854777856.0,
+      1e300,
+      -1e300
+    ]
+  }
+
+  // Each number on its own and as a table value, so it goes through both the
+  // top level and a table when loaded into lua.
+  fn blob(n: f64) -> Vec<u8> {
+    let table = LuabinsTable {
+      array_size: 1,
+      hash_size: 0,
+      entries: vec![(LuabinsValue::Number(1.0), LuabinsValue::Number(n))]
+    };
+    let mut bytes = Vec::new();
+    encode(&mut bytes, &[LuabinsValue::Number(n), LuabinsValue::Table(table)]).unwrap();
+    bytes
+  }
+
+  #[test]
+  fn sizes_stop_at_the_first_hole() {
+    let mut table = LuabinsTable {
+      array_size: 0,
+      hash_size: 0,
+      entries: [2.0, 1.0, 4.0, 0.5].iter().map(|n| (LuabinsValue::Number(*n), LuabinsValue::Boolean(true))).collect()
+    };
+    table.update_sizes();
+    assert_eq!((table.array_size, table.hash_size), (2, 2));
+  }
+
+  #[test]
+  fn edge_numbers_arent_integers() {
+    for n in edge_numbers() {
+      let expected = if n == -9223372036854775808.0 { Some(i64::MIN) } else { None };
+      assert_eq!(LuabinsValue::Number(n).as_integer(), expected, "{:016x}", n.to_bits());
+    }
+    assert_eq!(LuabinsValue::Number(9223372036854774784.0).as_integer(), Some(9223372036854774784));
+  }
+
+  #[test]
+  fn edge_numbers_reencode_identically() {
+    for n in edge_numbers() {
+      let bytes = blob(n);
+      let mut reencoded = Vec::new();
+      encode(&mut reencoded, &decode(&mut bytes.as_slice()).unwrap()).unwrap();
+      assert_eq!(reencoded, bytes, "{:016x}", n.to_bits());
+    }
+  }
+
+  #[test]
+  fn edge_numbers_survive_lua() {
+    let lua = rlua::Lua::new();
+    for n in edge_numbers() {
+      let bytes = blob(n);
+      let values = decode(&mut bytes.as_slice()).unwrap();
+      let reencoded = lua.context(|context| {
+        let values: Vec<LuabinsValue> = values.iter()
+          .map(|value| luavalue::from_lua(context, luavalue::to_lua(context, value).unwrap(), 0).unwrap())
+          .collect();
+        let mut reencoded = Vec::new();
+        encode(&mut reencoded, &values).unwrap();
+        reencoded
+      });
+      assert_eq!(reencoded, bytes, "{:016x}", n.to_bits());
+    }
+    // Lua gets 2^63 as a float, so arithmetic on it doesn't wrap.
+    lua.context(|context| {
+      let value = luavalue::to_lua(context, &LuabinsValue::Number(9223372036854775808.0)).unwrap();
+      assert!(matches!(value, rlua::Value::Number(_)), "{:?}", value);
+    });
+  }
+}
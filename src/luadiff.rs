@@ -0,0 +1,129 @@
+//! Structural differences between two saves: their header fields, and their
+//! lua states compared value by value.
+
+use crate::hadesfile::HadesSave;
+use crate::luabins::{LuabinsTable, LuabinsValue};
+
+use serde_json::Value;
+use std::collections::{HashMap, HashSet};
+
+pub enum Change<'a> {
+  Added(LuabinsValue<'a>),
+  Removed(LuabinsValue<'a>),
+  Changed(LuabinsValue<'a>, LuabinsValue<'a>)
+}
+
+pub struct Difference<'a> {
+  /// The table keys leading to the value, starting inside the top-level
+  /// table of globals; see luabins::path_string.
+  pub path: Vec<LuabinsValue<'a>>,
+  pub change: Change<'a>
+}
+
+// Table keys, hashable. Keys are matched the way luabins::LuabinsValue
+// compares them: numbers by their bits. Table keys only match themselves.
+#[derive(PartialEq, Eq, Hash)]
+enum KeyId<'k> {
+  Nil,
+  Boolean(bool),
+  Number(u64),
+  String(&'k [u8]),
+  Table(*const LuabinsTable<'k>)
+}
+
+impl<'k> KeyId<'k> {
+  fn of(key: &'k LuabinsValue) -> KeyId<'k> {
+    match key {
+      LuabinsValue::Nil => KeyId::Nil,
+      LuabinsValue::Boolean(b) => KeyId::Boolean(*b),
+      LuabinsValue::Number(n) => KeyId::Number(n.to_bits()),
+      LuabinsValue::String(s) => KeyId::String(s),
+      LuabinsValue::Table(t) => KeyId::Table(t)
+    }
+  }
+}
+
+fn diff_value<'a>(path: &mut Vec<LuabinsValue<'a>>, old: &LuabinsValue<'a>, new: &LuabinsValue<'a>, differences: &mut Vec<Difference<'a>>) {
+  match (old, new) {
+    (LuabinsValue::Table(old_table), LuabinsValue::Table(new_table)) => diff_table(path, old_table, new_table, differences),
+    _ if old == new => {},
+    _ => differences.push(Difference {
+      path: path.clone(),
+      change: Change::Changed(old.clone(), new.clone())
+    })
+  }
+}
+
+// Entries are matched by key; changes come in the order of the old table,
+// then additions in the order of the new one. Array and hash sizes aren't
+// compared, only contents.
+fn diff_table<'a>(path: &mut Vec<LuabinsValue<'a>>, old: &LuabinsTable<'a>, new: &LuabinsTable<'a>, differences: &mut Vec<Difference<'a>>) {
+  let new_values: HashMap<KeyId, &LuabinsValue<'a>> = new.entries.iter()
+    .map(|(key, value)| (KeyId::of(key), value))
+    .collect();
+  let mut old_keys = HashSet::new();
+  for (key, old_value) in old.entries.iter() {
+    let key_id = KeyId::of(key);
+    path.push(key.clone());
+    match new_values.get(&key_id) {
+      Some(new_value) => diff_value(path, old_value, new_value, differences),
+      None => differences.push(Difference {
+        path: path.clone(),
+        change: Change::Removed(old_value.clone())
+      })
+    }
+    path.pop();
+    old_keys.insert(key_id);
+  }
+  for (key, new_value) in new.entries.iter() {
+    if !old_keys.contains(&KeyId::of(key)) {
+      path.push(key.clone());
+      differences.push(Difference {
+        path: path.clone(),
+        change: Change::Added(new_value.clone())
+      });
+      path.pop();
+    }
+  }
+}
+
+/// The differences between two decoded lua states.
+pub fn diff<'a>(old: &[LuabinsValue<'a>], new: &[LuabinsValue<'a>]) -> Vec<Difference<'a>> {
+  let mut differences = Vec::new();
+  let mut path = Vec::new();
+  for i in 0..old.len().max(new.len()) {
+    match (old.get(i), new.get(i)) {
+      (Some(old_value), Some(new_value)) => diff_value(&mut path, old_value, new_value, &mut differences),
+      (Some(old_value), None) => differences.push(Difference { path: Vec::new(), change: Change::Removed(old_value.clone()) }),
+      (None, Some(new_value)) => differences.push(Difference { path: Vec::new(), change: Change::Added(new_value.clone()) }),
+      (None, None) => {}
+    }
+  }
+  differences
+}
+
+/// The header fields that differ, with their old and new values as in
+/// HadesSave::header_json. Fields only one of the saves has are compared
+/// against null.
+pub fn header_diff(old: &HadesSave, new: &HadesSave) -> Vec<(String, Value, Value)> {
+  let old_header = old.header_json();
+  let new_header = new.header_json();
+  let mut fields: Vec<String> = Vec::new();
+  for header in [&old_header, &new_header] {
+    if let Some(object) = header.as_object() {
+      for field in object.keys() {
+        if !fields.contains(field) {
+          fields.push(field.clone());
+        }
+      }
+    }
+  }
+  fields.into_iter()
+    .filter(|field| old_header[field.as_str()] != new_header[field.as_str()])
+    .map(|field| {
+      let old_value = old_header[field.as_str()].clone();
+      let new_value = new_header[field.as_str()].clone();
+      (field, old_value, new_value)
+    })
+    .collect()
+}
@@ -0,0 +1,356 @@
+//! JSON encodings of luabins values.
+//!
+//! The lossless encoding is the canonical export format, and the one
+//! `--from-json` expects. A dump is a list of the top-level values, each
+//! encoded as:
+//!
+//! | lua value         | json                                                        |
+//! |-------------------|-------------------------------------------------------------|
+//! | nil               | `null`                                                      |
+//! | boolean           | `true` / `false`                                            |
+//! | string            | `"text"`, or `{"bytes": [..]}` if it isn't valid utf-8      |
+//! | integer number    | `{"int": 5}`                                                |
+//! | float number      | `{"float": 0.5}`                                            |
+//! | -0.0, NaN, inf    | `{"float_bits": "8000000000000000"}` (hex of the f64 bits)  |
+//! | table             | `{"array_size": 2, "hash_size": 1, "entries": [[k, v], ..]}` |
+//!
+//! Table keys are encoded like any other value, so boolean, float and table
+//! keys survive, and entries are kept in the order they were read. Integer
+//! numbers are the ones that convert to an i64 and back bit-for-bit. Floats
+//! are written as the shortest decimal that reads back as the same f64, which
+//! relies on serde_json's float_roundtrip feature to read them exactly.
+//!
+//! A save is dumped as its header_json, with the dump under `lua_state` and
+//! the bytes that follow the blob (see HadesSave::lua_state_tail) under
+//! `lua_state_tail`, as `{"zeros": 64}` or, if they aren't all zeros,
+//! `{"bytes": [..]}`. The tail is left out when there isn't one.
+//!
+//! The human encoding is the older, more readable format: tables become
+//! objects keyed by string, integer keys are written as `"[5]"`, other keys
+//! are dropped and integers and floats aren't distinguished.
+
+use crate::error::{Result, SaveError};
+use crate::luabins::{LuabinsTable, LuabinsValue};
+use serde_json::{json, Value};
+use std::borrow::Cow;
+
+/// The lossless encoding of the values.
+pub fn dump(values: &[LuabinsValue]) -> Value {
+    Value::Array(values.iter().map(dump_value).collect())
+}
+
+/// The lossless encoding of a single value.
+pub fn dump_value(value: &LuabinsValue) -> Value {
+    match value {
+        LuabinsValue::Nil => json!(null),
+        LuabinsValue::Boolean(b) => json!(b),
+        LuabinsValue::Number(n) => {
+            if let Some(i) = value.as_integer() {
+                json!({ "int": i })
+            } else if n.is_finite() && !(*n == 0.0 && n.is_sign_negative()) {
+                json!({ "float": n })
+            } else {
+                json!({ "float_bits": format!("{:016x}", n.to_bits()) })
+            }
+        },
+        LuabinsValue::String(s) => match std::str::from_utf8(s) {
+            Ok(s) => json!(s),
+            Err(_) => json!({ "bytes": s })
+        },
+        LuabinsValue::Table(t) => {
+            let entries: Vec<Value> = t.entries.iter()
+                .map(|(key, value)| json!([dump_value(key), dump_value(value)]))
+                .collect();
+            json!({
+                "array_size": t.array_size,
+                "hash_size": t.hash_size,
+                "entries": entries
+            })
+        }
+    }
+}
+
+/// The encoding of the bytes after the luabins blob.
+pub fn dump_tail(tail: &[u8]) -> Value {
+    if tail.iter().all(|b| *b == 0) {
+        json!({ "zeros": tail.len() })
+    } else {
+        json!({ "bytes": tail })
+    }
+}
+
+/// Reads the bytes after the luabins blob; null, for a dump without them, is
+/// no bytes.
+pub fn load_tail(value: &Value) -> Result<Vec<u8>> {
+    if value.is_null() {
+        return Ok(Vec::new());
+    }
+    if let Some(zeros) = value.get("zeros") {
+        let zeros = zeros.as_u64()
+            .and_then(|zeros| usize::try_from(zeros).ok())
+            .ok_or_else(|| SaveError::json(format!("expected a count, got {}", zeros)).within("zeros"))?;
+        return Ok(vec![0; zeros]);
+    }
+    match load_value(value)? {
+        LuabinsValue::String(bytes) if value.get("bytes").is_some() => Ok(bytes.into_owned()),
+        _ => Err(SaveError::json(format!("expected zeros or bytes, got {}", value)))
+    }
+}
+
+/// Reads either encoding; lossless dumps are lists, human ones objects.
+pub fn load(value: &Value) -> Result<Vec<LuabinsValue<'_>>> {
+    match value {
+        Value::Array(values) => values.iter()
+            .enumerate()
+            .map(|(i, value)| load_value(value).map_err(|err| err.within(&format!("[{}]", i))))
+            .collect(),
+        _ => load_human(value)
+    }
+}
+
+/// Reads a single value in the lossless encoding.
+pub fn load_value(value: &Value) -> Result<LuabinsValue<'_>> {
+    match value {
+        Value::Null => Ok(LuabinsValue::Nil),
+        Value::Bool(b) => Ok(LuabinsValue::Boolean(*b)),
+        Value::String(s) => Ok(LuabinsValue::String(Cow::Borrowed(s.as_bytes()))),
+        Value::Object(object) => {
+            if let Some(i) = object.get("int") {
+                let i = i.as_i64().ok_or_else(|| SaveError::json(format!("expected an integer, got {}", i)).within("int"))?;
+                Ok(LuabinsValue::Number(i as f64))
+            } else if let Some(n) = object.get("float") {
+                let n = n.as_f64().ok_or_else(|| SaveError::json(format!("expected a number, got {}", n)).within("float"))?;
+                Ok(LuabinsValue::Number(n))
+            } else if let Some(bits) = object.get("float_bits") {
+                let bits = bits.as_str()
+                    .and_then(|bits| u64::from_str_radix(bits, 16).ok())
+                    .ok_or_else(|| SaveError::json(format!("expected hex float bits, got {}", bits)).within("float_bits"))?;
+                Ok(LuabinsValue::Number(f64::from_bits(bits)))
+            } else if let Some(bytes) = object.get("bytes") {
+                let bytes = bytes.as_array()
+                    .ok_or_else(|| SaveError::json(format!("expected a list of bytes, got {}", bytes)))
+                    .and_then(|bytes| bytes.iter()
+                        .map(|b| b.as_u64().and_then(|b| u8::try_from(b).ok()).ok_or_else(|| SaveError::json(format!("expected a byte, got {}", b))))
+                        .collect::<Result<Vec<u8>>>())
+                    .map_err(|err| err.within("bytes"))?;
+                Ok(LuabinsValue::String(Cow::Owned(bytes)))
+            } else if let Some(entries) = object.get("entries") {
+                let size = |name: &str| -> Result<i32> {
+                    object.get(name)
+                        .and_then(|size| size.as_i64())
+                        .and_then(|size| i32::try_from(size).ok())
+                        .ok_or_else(|| SaveError::json(format!("expected {} in {}", name, value)))
+                };
+                let entries = entries.as_array()
+                    .ok_or_else(|| SaveError::json(format!("expected a list of entries, got {}", entries)))
+                    .and_then(|entries| entries.iter()
+                        .enumerate()
+                        .map(|(i, entry)| match entry.as_array().map(|pair| pair.as_slice()) {
+                            Some([key, value]) => Ok((
+                                load_value(key).map_err(|err| err.within("[0]"))?,
+                                load_value(value).map_err(|err| err.within("[1]"))?
+                            )),
+                            _ => Err(SaveError::json(format!("expected a [key, value] entry, got {}", entry)))
+                        }.map_err(|err| err.within(&format!("[{}]", i))))
+                        .collect::<Result<Vec<(LuabinsValue, LuabinsValue)>>>())
+                    .map_err(|err| err.within("entries"))?;
+                Ok(LuabinsValue::Table(LuabinsTable {
+                    array_size: size("array_size")?,
+                    hash_size: size("hash_size")?,
+                    entries: entries
+                }))
+            } else {
+                Err(SaveError::json(format!("unknown value {}", value)))
+            }
+        },
+        _ => Err(SaveError::json(format!("unexpected {}", value)))
+    }
+}
+
+/// The human encoding of the values.
+pub fn dump_human(values: &[LuabinsValue]) -> Result<Value> {
+    let mut table = json!({});
+    for (i, value) in values.iter().enumerate() {
+        let key = format!("[{}]", i);
+        table[&key] = dump_human_value(value).map_err(|err| err.within(&key))?
+    }
+    Ok(table)
+}
+
+/// The human encoding of a single value.
+pub fn dump_human_value(value: &LuabinsValue) -> Result<Value> {
+    match value {
+        LuabinsValue::Boolean(b) => Ok(json!(b)),
+        LuabinsValue::Nil => Ok(json!(null)),
+        LuabinsValue::Number(n) => match value.as_integer() {
+            Some(i) => Ok(json!(i)),
+            None => Ok(json!(n))
+        },
+        LuabinsValue::String(s) => Ok(json!(human_string(s)?)),
+        LuabinsValue::Table(t) => {
+            let mut table = json!({});
+            for (key, value) in t.entries.iter() {
+                match key {
+                    LuabinsValue::String(s) => {
+                        let key = human_string(s)?;
+                        table[key] = dump_human_value(value).map_err(|err| err.within(key))?
+                    },
+                    LuabinsValue::Number(_) => {
+                        if let Some(i) = key.as_integer() {
+                            let key = format!("[{}]", i);
+                            table[&key] = dump_human_value(value).map_err(|err| err.within(&key))?
+                        }
+                    },
+                    _ => {}
+                }
+            }
+            Ok(table)
+        }
+    }
+}
+
+// Strings the human encoding can't show, it refuses rather than mangle.
+fn human_string(s: &[u8]) -> Result<&str> {
+    std::str::from_utf8(s).map_err(|_| SaveError::json("not valid utf-8; use the lossless encoding"))
+}
+
+fn load_human(value: &Value) -> Result<Vec<LuabinsValue<'_>>> {
+    let mut indexed = Vec::new();
+    for (key, value) in value.as_object().ok_or_else(|| SaveError::json(format!("expected an object, got {}", value)))? {
+        match load_human_key(key).as_integer() {
+            Some(i) => indexed.push((i, load_human_value(value).map_err(|err| err.within(key))?)),
+            None => return Err(SaveError::json(format!("expected an index, got {}", key)))
+        }
+    }
+    indexed.sort_by_key(|(i, _)| *i);
+    Ok(indexed.into_iter().map(|(_, value)| value).collect())
+}
+
+// "[5]" keys are integers, everything else a string. Array and hash sizes
+// aren't in the human encoding, so they're worked out the way luabins would.
+fn load_human_value(value: &Value) -> Result<LuabinsValue<'_>> {
+    match value {
+        Value::Null => Ok(LuabinsValue::Nil),
+        Value::Bool(b) => Ok(LuabinsValue::Boolean(*b)),
+        Value::Number(n) => match n.as_i64() {
+            Some(i) => Ok(LuabinsValue::Number(i as f64)),
+            None => Ok(LuabinsValue::Number(n.as_f64().unwrap()))
+        },
+        Value::String(s) => Ok(LuabinsValue::String(Cow::Borrowed(s.as_bytes()))),
+        Value::Array(_) => Err(SaveError::json(format!("unexpected array {}", value))),
+        Value::Object(values) => {
+            let mut entries = Vec::new();
+            for (key, value) in values {
+                entries.push((load_human_key(key), load_human_value(value).map_err(|err| err.within(key))?));
+            }
+            let mut table = LuabinsTable { array_size: 0, hash_size: 0, entries: entries };
+            table.update_sizes();
+            Ok(LuabinsValue::Table(table))
+        }
+    }
+}
+
+fn load_human_key(key: &str) -> LuabinsValue<'_> {
+    match key.strip_prefix("[").and_then(|k| k.strip_suffix("]")).and_then(|k| k.parse::<i64>().ok()) {
+        Some(i) => LuabinsValue::Number(i as f64),
+        None => LuabinsValue::String(Cow::Borrowed(key.as_bytes()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::hadesfile;
+    use crate::luabins;
+    use crate::HadesSave;
+
+    fn roundtrip(n: f64) {
+        let text = dump_value(&LuabinsValue::Number(n)).to_string();
+        let parsed: Value = serde_json::from_str(&text).unwrap();
+        match load_value(&parsed).unwrap() {
+            LuabinsValue::Number(m) => assert_eq!(m.to_bits(), n.to_bits(), "{} came back as {}", text, m),
+            other => panic!("{} came back as {:?}", text, other)
+        }
+    }
+
+    #[test]
+    fn floats_roundtrip_bit_for_bit() {
+        for n in [
+            0.1, 1.0 / 3.0, -2.5, 1e300, -1e-300, 9.223372036854776e18, 5e-324,
+            f64::MIN_POSITIVE, f64::from_bits(0x000fffffffffffff), 0.30000000000000004,
+            -0.0, f64::INFINITY, f64::NEG_INFINITY, f64::from_bits(0x7ff8000000000001)
+        ] {
+            roundtrip(n);
+        }
+        // Arbitrary bit patterns, from a fixed xorshift sequence.
+        let mut state: u64 = 0x9e3779b97f4a7c15;
+        for _ in 0..100000 {
+            state ^= state << 13;
+            state ^= state >> 7;
+            state ^= state << 17;
+            roundtrip(f64::from_bits(state));
+        }
+    }
+
+    fn string(s: &str) -> LuabinsValue<'static> {
+        LuabinsValue::String(Cow::Owned(s.as_bytes().to_vec()))
+    }
+
+    fn table(array_size: i32, entries: Vec<(LuabinsValue<'static>, LuabinsValue<'static>)>) -> LuabinsValue<'static> {
+        LuabinsValue::Table(LuabinsTable { array_size: array_size, hash_size: entries.len() as i32 - array_size, entries: entries })
+    }
+
+    #[test]
+    fn lua_state_roundtrips_through_json() {
+        let run = table(2, vec![
+            (LuabinsValue::Number(1.0), string("Boon")),
+            (LuabinsValue::Number(2.0), LuabinsValue::Number(0.1)),
+            (string("Depth"), LuabinsValue::Number(-12.0)),
+            (LuabinsValue::Boolean(true), LuabinsValue::Number(-0.0)),
+            (LuabinsValue::Number(2.5), LuabinsValue::Number(f64::from_bits(0x7ff8000000000001))),
+            (string("Huge"), LuabinsValue::Number(9223372036854775808.0)),
+            (string("Bytes"), LuabinsValue::String(Cow::Owned(vec![0xff, 0x00, 0x80]))),
+            (string("Nested"), table(0, vec![(string("Cleared"), LuabinsValue::Boolean(false))]))
+        ]);
+        let globals = table(0, vec![
+            (string("CurrentRun"), run),
+            (string("GameState"), table(0, vec![(string("Gems"), LuabinsValue::Number(1.0 / 3.0))]))
+        ]);
+        let mut lua_state = Vec::new();
+        luabins::encode(&mut lua_state, &[globals, LuabinsValue::Nil]).unwrap();
+
+        let text = dump(&luabins::decode(&mut lua_state.as_slice()).unwrap()).to_string();
+        let parsed: Value = serde_json::from_str(&text).unwrap();
+        let mut reencoded = Vec::new();
+        luabins::encode(&mut reencoded, &load(&parsed).unwrap()).unwrap();
+        assert_eq!(reencoded, lua_state);
+    }
+
+    // What --json writes and --from-json reads back, for a save laid out the
+    // way the game writes it.
+    #[test]
+    fn save_roundtrips_through_json() {
+        let file = hadesfile::tests::PADDED_SAVE;
+        let save = hadesfile::read(&mut &file[..], false).unwrap();
+        let mut document = save.header_json();
+        document["lua_state"] = dump(&luabins::decode(&mut save.lua_state()).unwrap());
+        document["lua_state_tail"] = dump_tail(save.lua_state_tail());
+        let document: Value = serde_json::from_str(&document.to_string()).unwrap();
+        assert_eq!(document["lua_state_tail"], json!({ "zeros": 64 }));
+
+        let mut lua_state = Vec::new();
+        luabins::encode(&mut lua_state, &load(&document["lua_state"]).unwrap()).unwrap();
+        let mut loaded = HadesSave::from_header_json(&document, lua_state).unwrap();
+        loaded.set_lua_state_tail(load_tail(&document["lua_state_tail"]).unwrap());
+        assert_eq!(hadesfile::write(&loaded, false).unwrap().0, file);
+    }
+
+    #[test]
+    fn tails_roundtrip_through_json() {
+        for tail in [&[][..], &[0; 3][..], &[0, 7, 0][..]] {
+            let text = dump_tail(tail).to_string();
+            assert_eq!(load_tail(&serde_json::from_str(&text).unwrap()).unwrap(), tail, "{}", text);
+        }
+        assert_eq!(load_tail(&Value::Null).unwrap(), Vec::<u8>::new());
+    }
+}
@@ -0,0 +1,125 @@
+//! Patches: the changes between two saves as a json document, which can be
+//! reviewed, edited by hand and applied to another save.
+//!
+//! ```text
+//! {
+//!   "header": { "runs": 43 },
+//!   "lua_state": [
+//!     { "path": ["GameState", "Resources", "MetaPoints"], "value": { "int": 7 } },
+//!     { "path": ["CurrentRun", "Hero", { "int": 2 }], "value": "Shield" },
+//!     { "path": ["GameState", "Flag"], "delete": true }
+//!   ]
+//! }
+//! ```
+//!
+//! Paths are the table keys from the globals down, and values are in the
+//! lossless encoding of luajson. Setting a value replaces it, or adds it if
+//! the key isn't there; the tables along the way must exist. Header fields
+//! are set as in HadesSave::header_json, except for version and lua_keys,
+//! which follows the globals.
+
+use crate::error::{Result, SaveError};
+use crate::hadesfile::{self, HadesSave, Warning};
+use crate::luabins::{self, LuabinsValue};
+use crate::luadiff::{self, Change};
+use crate::luajson;
+
+use serde_json::{json, Map, Value};
+
+/// The patch that turns old into new.
+pub fn generate(old: &HadesSave, new: &HadesSave) -> Result<Value> {
+  let mut header = Map::new();
+  for (field, _, new_value) in luadiff::header_diff(old, new) {
+    if field != "version" && field != "lua_keys" && !new_value.is_null() {
+      header.insert(field, new_value);
+    }
+  }
+  let old_state = luabins::decode(&mut old.lua_state())?;
+  let new_state = luabins::decode(&mut new.lua_state())?;
+  let lua_state = luadiff::diff(&old_state, &new_state).iter()
+    .map(|difference| {
+      if difference.path.is_empty() {
+        return Err(SaveError::Patch("the saves have different numbers of top-level values".to_owned()));
+      }
+      let path = luajson::dump(&difference.path);
+      Ok(match &difference.change {
+        Change::Added(value) | Change::Changed(_, value) => json!({ "path": path, "value": luajson::dump_value(value) }),
+        Change::Removed(_) => json!({ "path": path, "delete": true })
+      })
+    })
+    .collect::<Result<Vec<Value>>>()?;
+  Ok(json!({ "header": header, "lua_state": lua_state }))
+}
+
+// Sets or, given no value, deletes the value at the path. Failures are
+// described for SaveError::Patch.
+fn apply_edit<'a>(values: &mut [LuabinsValue<'a>], path: &[LuabinsValue<'a>], value: Option<LuabinsValue<'a>>) -> std::result::Result<(), String> {
+  let (last, parents) = path.split_last().ok_or_else(|| "empty path".to_owned())?;
+  let mut table = match values.first_mut() {
+    Some(LuabinsValue::Table(table)) => table,
+    _ => return Err("the lua state has no table of globals".to_owned())
+  };
+  for (depth, key) in parents.iter().enumerate() {
+    table = match table.entries.iter_mut().find(|(table_key, _)| table_key == key) {
+      Some((_, LuabinsValue::Table(table))) => table,
+      _ => return Err(format!("{} is not a table", luabins::path_string(&path[..=depth])))
+    };
+  }
+  let position = table.entries.iter().position(|(table_key, _)| table_key == last);
+  match (position, value) {
+    (Some(i), Some(value)) => table.entries[i].1 = value,
+    (None, Some(value)) => {
+      table.entries.push((last.clone(), value));
+      table.update_sizes();
+    },
+    (Some(i), None) => {
+      table.entries.remove(i);
+      table.update_sizes();
+    },
+    (None, None) => {}
+  }
+  Ok(())
+}
+
+/// Applies the patch to the save, returning the changes to lua_keys; see
+/// hadesfile::sync_lua_keys.
+pub fn apply(patch: &Value, save: &mut HadesSave) -> Result<Vec<Warning>> {
+  if let Some(header) = patch.get("header") {
+    let fields = header.as_object()
+      .ok_or_else(|| SaveError::json(format!("expected an object, got {}", header)).within("header"))?;
+    let mut new_header = save.header_json();
+    for (field, value) in fields.iter() {
+      if field == "version" || field == "lua_keys" {
+        return Err(SaveError::json("can't be patched").within(field).within("header"));
+      }
+      new_header[field] = value.clone();
+    }
+    save.set_header_json(&new_header).map_err(|err| err.within("header"))?;
+  }
+
+  let edits = patch["lua_state"].as_array()
+    .ok_or_else(|| SaveError::json(format!("expected a list of edits, got {}", patch["lua_state"])).within("lua_state"))?;
+  let mut lua_state = Vec::new();
+  {
+    let mut values = luabins::decode(&mut save.lua_state())?;
+    for (i, edit) in edits.iter().enumerate() {
+      let within_edit = |err: SaveError| err.within(&format!("[{}]", i)).within("lua_state");
+      let path = edit["path"].as_array()
+        .ok_or_else(|| SaveError::json(format!("expected a list of keys, got {}", edit["path"])))
+        .and_then(|keys| keys.iter()
+          .enumerate()
+          .map(|(j, key)| luajson::load_value(key).map_err(|err| err.within(&format!("[{}]", j))))
+          .collect::<Result<Vec<LuabinsValue>>>())
+        .map_err(|err| within_edit(err.within("path")))?;
+      let value = match (edit.get("value"), edit.get("delete")) {
+        (Some(value), None) => Some(luajson::load_value(value).map_err(|err| within_edit(err.within("value")))?),
+        (None, Some(Value::Bool(true))) => None,
+        _ => return Err(within_edit(SaveError::json("expected either a value or \"delete\": true")))
+      };
+      apply_edit(&mut values, &path, value).map_err(|message| SaveError::Patch(format!("lua_state[{}]: {}", i, message)))?;
+    }
+    luabins::encode(&mut lua_state, &values)?;
+  }
+  save.set_lua_state(lua_state);
+  hadesfile::sync_lua_keys(save)
+}
@@ -0,0 +1,122 @@
+use anyhow::{anyhow, bail, Result};
+use rlua::{Context, Value, FromLua};
+
+// A step in a path from the globals down to a value, as shown in the gui and
+// given to get and set: GameState.Resources.MetaPoints, CurrentRun.Hero[2].
+#[derive(Clone, Debug, PartialEq, PartialOrd, Eq, Ord)]
+pub enum TableKey {
+    StringKey(String),
+    NumberKey(i64)
+}
+
+pub fn lua_get_path<'a, P: IntoIterator<Item = TableKey>>(lua_ctx: Context<'a>, lua_path: P) -> Result<Value<'a>> {
+    let mut current_value: Value = Value::Table(lua_ctx.globals());
+    for segment in lua_path {
+        match current_value {
+            Value::Table(table_value) => {
+                match segment {
+                    TableKey::NumberKey(i) => {
+                        current_value = table_value.get(i)?;
+                    },
+                    TableKey::StringKey(s) => {
+                        current_value = table_value.get(s)?;
+                    }
+                }
+            },
+            _ => bail!("not a table! {:?}", current_value)
+        }
+    }
+    Ok(current_value)
+}
+
+pub fn lua_set_path<'a>(lua_ctx: Context<'a>, lua_path: &[TableKey], value: Value<'a>) -> Result<()> {
+    let (last, parent_path) = lua_path.split_last().ok_or_else(|| anyhow!("empty path"))?;
+    match lua_get_path(lua_ctx, parent_path.iter().cloned())? {
+        Value::Table(table_value) => {
+            match last {
+                TableKey::NumberKey(i) => table_value.set(*i, value)?,
+                TableKey::StringKey(s) => table_value.set(s.as_str(), value)?
+            }
+        },
+        parent => bail!("{} is not a table! {:?}", lua_path_as_string(parent_path), parent)
+    }
+    Ok(())
+}
+
+pub fn lua_path_as_string<'a, P: IntoIterator<Item = &'a TableKey>>(lua_path: P) -> String {
+    let mut path_string: String = "".to_owned();
+    for segment in lua_path {
+        match segment {
+            TableKey::NumberKey(i) => {
+                path_string.push_str(&format!("[{}]", i))
+            },
+            TableKey::StringKey(s) => {
+                if !path_string.is_empty() {
+                    path_string.push('.');
+                }
+                path_string.push_str(s);
+            }
+        }
+    }
+    path_string
+}
+
+// Inverse of lua_path_as_string.
+pub fn lua_path_from_string(path_string: &str) -> Result<Vec<TableKey>> {
+    let mut lua_path = Vec::new();
+    let mut rest = path_string;
+    while !rest.is_empty() {
+        if let Some(index) = rest.strip_prefix('[') {
+            let end = index.find(']').ok_or_else(|| anyhow!("unclosed [ in {}", path_string))?;
+            let i = index[..end].trim().parse::<i64>()
+                .map_err(|_| anyhow!("expected a number in [], got {}", &index[..end]))?;
+            lua_path.push(TableKey::NumberKey(i));
+            rest = &index[end + 1..];
+        } else {
+            let name = if lua_path.is_empty() { rest } else {
+                rest.strip_prefix('.').ok_or_else(|| anyhow!("expected . or [ at {}", rest))?
+            };
+            let end = name.find(['.', '[']).unwrap_or(name.len());
+            if end == 0 {
+                bail!("empty name in {}", path_string);
+            }
+            lua_path.push(TableKey::StringKey(name[..end].to_owned()));
+            rest = &name[end..];
+        }
+    }
+    Ok(lua_path)
+}
+
+pub fn lua_to_string<'a>(value: Value<'a>, lua_ctx: Context<'a>) -> Result<String> {
+    let lua_string = match value {
+        Value::Nil => "nil".to_owned(),
+        Value::Boolean(boolean_value) => {
+            if boolean_value { "true".to_owned() } else { "false".to_owned() }
+        },
+        Value::Integer(_) => {
+            String::from_lua(value, lua_ctx)?
+        },
+        Value::Number(_) => {
+            String::from_lua(value, lua_ctx)?
+        },
+        Value::String(_) => {
+            format!("\"{}\"", String::from_lua(value, lua_ctx)?)
+        },
+        Value::Table(_) => {
+            "table".to_owned()
+        },
+        Value::Function(_) => {
+            "function".to_owned()
+        },
+        Value::Thread(_) => {
+            "thread".to_owned()
+        },
+        Value::UserData(_) | Value::LightUserData(_) => {
+            "userdata".to_owned()
+        },
+        Value::Error(err) => {
+            format!("error: {}", err)
+        },
+    };
+    Ok(lua_string)
+}
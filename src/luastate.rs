@@ -1,10 +1,122 @@
+//! A save's lua state and header, loaded into a lua interpreter for editing.
+
+use crate::hadesfile;
+use crate::hadesfile::{HadesSave, HadesSaveV16, HadesSaveV17, SaveVersion, Warning};
 use crate::luabins;
-use anyhow::Result;
-use rlua::{Lua, Value};
+use crate::luavalue;
+use crate::error::{Result, SaveError};
+use rlua::{Context, Lua, Table, Value};
+use serde_json::{json, Value as JsonValue};
+
+/// Loads the save's lua state into globals, and its header fields into the
+/// SaveHeader table so scripts can edit them too.
+pub fn load(lua: &Lua, savedata: &HadesSave) -> Result<()> {
+    load_state(lua, &mut savedata.lua_state())?;
+    lua.context(|lua_ctx| -> Result<()> {
+        let header = json_to_lua(lua_ctx, &savedata.header_json())?;
+        lua_ctx.globals().set("SaveHeader", header)?;
+        Ok(())
+    })
+}
+
+/// Writes globals and SaveHeader back into the save, updating lua_keys to
+/// match the globals that were saved; see hadesfile::sync_lua_keys.
+pub fn save(lua: &Lua, savedata: &mut HadesSave) -> Result<Vec<Warning>> {
+    let header = lua.context(|lua_ctx| -> Result<JsonValue> {
+        let header: Value = lua_ctx.globals().get("SaveHeader")?;
+        lua_to_json(header)
+    })?;
+    savedata.set_header_json(&header)?;
+    savedata.set_lua_state(save_state(lua)?);
+    hadesfile::sync_lua_keys(savedata)
+}
+
+// Header fields are only numbers, booleans, strings and lists of those.
+fn json_to_lua<'lua>(lua_ctx: Context<'lua>, value: &JsonValue) -> Result<Value<'lua>> {
+    let lua_value = match value {
+        JsonValue::Bool(b) => Value::Boolean(*b),
+        JsonValue::Number(n) => match n.as_i64() {
+            Some(i) => Value::Integer(i),
+            None => Value::Number(n.as_f64().unwrap())
+        },
+        JsonValue::String(s) => Value::String(lua_ctx.create_string(s)?),
+        JsonValue::Array(values) => {
+            let table = lua_ctx.create_table()?;
+            for (i, value) in values.iter().enumerate() {
+                table.set(i + 1, json_to_lua(lua_ctx, value)?)?;
+            }
+            Value::Table(table)
+        },
+        JsonValue::Object(values) => {
+            let table = lua_ctx.create_table()?;
+            for (key, value) in values.iter() {
+                table.set(key.as_str(), json_to_lua(lua_ctx, value)?)?;
+            }
+            Value::Table(table)
+        },
+        JsonValue::Null => Value::Nil
+    };
+    Ok(lua_value)
+}
+
+fn not_json(type_name: &'static str) -> SaveError {
+    rlua::Error::FromLuaConversionError { from: type_name, to: "json", message: None }.into()
+}
+
+// Inverse of json_to_lua. Tables with a sequence part become lists.
+fn lua_to_json(value: Value) -> Result<JsonValue> {
+    let json_value = match value {
+        Value::Nil => JsonValue::Null,
+        Value::Boolean(b) => json!(b),
+        Value::Integer(i) => json!(i),
+        Value::Number(n) => json!(n),
+        Value::String(s) => json!(s.to_str()?),
+        Value::Table(table) => {
+            if table.raw_len() > 0 || table.clone().pairs::<Value, Value>().next().is_none() {
+                let values = table.sequence_values::<Value>()
+                    .map(|value| lua_to_json(value?))
+                    .collect::<Result<Vec<JsonValue>>>()?;
+                JsonValue::Array(values)
+            } else {
+                let mut object = json!({});
+                for pair in table.pairs::<String, Value>() {
+                    let (key, value) = pair?;
+                    object[key] = lua_to_json(value)?;
+                }
+                object
+            }
+        },
+        Value::Function(_) => return Err(not_json("function")),
+        Value::Thread(_) => return Err(not_json("thread")),
+        Value::UserData(_) | Value::LightUserData(_) => return Err(not_json("userdata")),
+        Value::Error(_) => return Err(not_json("error"))
+    };
+    Ok(json_value)
+}
 
-pub fn load(lua: &Lua, lua_state: &mut &[u8]) -> Result<()> {
+const GLOBAL_LAYOUT: &str = "luastate_global_layout";
+
+fn load_state(lua: &Lua, lua_state: &mut &[u8]) -> Result<()> {
     lua.context(|lua_ctx| -> Result<()> {
-        let save_data = luabins::load(lua_state, lua_ctx)?;
+        let save_data = luavalue::load(lua_ctx, &luabins::decode(lua_state)?)?;
+        // Globals are loaded into _G, so remember their layout for saving.
+        if let Some(Value::Table(saved_values)) = save_data.first() {
+            if let Some(layout) = luavalue::layout(lua_ctx, saved_values)? {
+                lua_ctx.set_named_registry_value(GLOBAL_LAYOUT, layout)?;
+            }
+        }
+        if lua_ctx.named_registry_value::<_, bool>(WHITELIST_FROM_STATE)? {
+            let whitelist: Table = lua_ctx.globals().get("GlobalSaveWhitelist")?;
+            for saved_values in save_data.iter() {
+                if let Value::Table(saved_values) = saved_values {
+                    for (key, _) in luavalue::ordered_pairs(lua_ctx, saved_values)? {
+                        if let Value::String(key) = key {
+                            whitelist.raw_set(whitelist.raw_len() + 1, key)?;
+                        }
+                    }
+                }
+            }
+        }
         lua_ctx.globals().set("_saveData", save_data)?;
         // put save file data into globals
         lua_ctx.load(r#"
@@ -16,11 +128,12 @@ pub fn load(lua: &Lua, lua_state: &mut &[u8]) -> Result<()> {
                 end
             end
             _saveData = nil
-        "#).exec().map_err(anyhow::Error::new)
+        "#).exec()?;
+        Ok(())
     })
 }
 
-pub fn save(lua: &Lua) -> Result<Vec<u8>> {
+fn save_state(lua: &Lua) -> Result<Vec<u8>> {
     let mut new_lua_state: Vec<u8> = Vec::new();
     lua.context(|lua_ctx| -> Result<()> {
         // read save file data from
@@ -48,13 +161,62 @@ pub fn save(lua: &Lua) -> Result<Vec<u8>> {
                 end
             end
 
-        "#).exec().map_err(anyhow::Error::new)?;
-        let save_data: Vec<Value> = lua_ctx.globals().get("_saveData").map_err(anyhow::Error::new)?;
-        luabins::save(&mut new_lua_state, save_data)
+        "#).exec()?;
+        let save_data: Vec<Value> = lua_ctx.globals().get("_saveData")?;
+        if let (Some(Value::Table(saved_values)), Some(layout)) = (save_data.first(), lua_ctx.named_registry_value::<_, Option<Table>>(GLOBAL_LAYOUT)?) {
+            luavalue::set_layout(lua_ctx, saved_values, layout)?;
+        }
+        luabins::encode(&mut new_lua_state, &luavalue::save(lua_ctx, save_data)?)
     })?;
     Ok(new_lua_state)
 }
 
+/// Sets up the whitelist / ignore list the game uses for this save version.
+/// Which globals other versions keep isn't known, so for those the whitelist
+/// is the globals the loaded state already has: exactly what the game wrote
+/// is saved back, and globals a script adds aren't.
+pub fn initialize(lua: &Lua, version: u32) -> Result<()> {
+    match version {
+        HadesSaveV16::VERSION => initialize_v16(lua),
+        HadesSaveV17::VERSION => initialize_v17(lua),
+        _ => initialize_from_state(lua)
+    }
+}
+
+/// Re-saves a lua state the way the given version's game would, returning the
+/// new state and the top-level globals that version does not keep.
+pub fn convert(lua_state: &[u8], version: u32) -> Result<(Vec<u8>, Vec<String>)> {
+    let lua = Lua::new();
+    initialize(&lua, version)?;
+    load_state(&lua, &mut &lua_state[..])?;
+    let new_lua_state = save_state(&lua)?;
+
+    let kept: Vec<String> = luabins::top_level_sizes(&new_lua_state)?
+        .into_iter()
+        .filter_map(|(key, _)| key)
+        .collect();
+    let dropped = luabins::top_level_sizes(lua_state)?
+        .into_iter()
+        .filter_map(|(key, _)| key)
+        .filter(|key| !kept.contains(key))
+        .collect();
+    Ok((new_lua_state, dropped))
+}
+
+const WHITELIST_FROM_STATE: &str = "luastate_whitelist_from_state";
+
+// load_state fills in the whitelist.
+fn initialize_from_state(lua: &Lua) -> Result<()> {
+    lua.context(|lua_ctx| -> Result<()> {
+        lua_ctx.load(r#"
+            GlobalSaveWhitelist = {}
+            SaveIgnores = {}
+        "#).exec()?;
+        lua_ctx.set_named_registry_value(WHITELIST_FROM_STATE, true)?;
+        Ok(())
+    })
+}
+
 pub fn initialize_v17(lua: &Lua) -> Result<()> {
     lua.context(|lua_ctx| -> Result<()> {
         lua_ctx.load(r#"
@@ -72,7 +234,8 @@ pub fn initialize_v17(lua: &Lua) -> Result<()> {
                 "NextSeeds",
             }
             SaveIgnores = {}
-        "#).exec().map_err(anyhow::Error::new)
+        "#).exec()?;
+        Ok(())
     })
 }
 
@@ -146,6 +309,7 @@ pub fn initialize_v16(lua: &Lua) -> Result<()> {
                 "EncounterSaveBlacklist",
                 "RunSaveWhitelist",
                 "_saveData",
+                "SaveHeader",
                 "HotLoadInfo",
                 "ForceEvent",
                 "BlockHeroDeath",
@@ -312,6 +476,111 @@ pub fn initialize_v16(lua: &Lua) -> Result<()> {
             } ) do
                 SaveIgnores[value] = true
             end
-        "#).exec().map_err(anyhow::Error::new)
+        "#).exec()?;
+        Ok(())
     })
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::luabins::{LuabinsTable, LuabinsValue};
+    use std::borrow::Cow;
+
+    fn string(s: &str) -> LuabinsValue<'static> {
+        LuabinsValue::String(Cow::Owned(s.as_bytes().to_vec()))
+    }
+
+    fn table(array_size: i32, entries: Vec<(LuabinsValue<'static>, LuabinsValue<'static>)>) -> LuabinsValue<'static> {
+        LuabinsValue::Table(LuabinsTable { array_size: array_size, hash_size: entries.len() as i32 - array_size, entries: entries })
+    }
+
+    // Globals, and keys within them, in no particular order; all of them ones
+    // both v16 and v17 keep.
+    fn lua_state() -> Vec<u8> {
+        let game_state = table(2, vec![
+            (LuabinsValue::Number(2.0), string("Second")),
+            (string("Zeta"), LuabinsValue::Number(1.0)),
+            (LuabinsValue::Number(1.0), string("First")),
+            (string("Alpha"), LuabinsValue::Number(0.5)),
+            (LuabinsValue::Boolean(false), table(0, vec![(string("Y"), string("y")), (string("X"), LuabinsValue::Boolean(true))]))
+        ]);
+        let globals = table(0, vec![
+            (string("NextSeeds"), table(1, vec![(LuabinsValue::Number(1.0), LuabinsValue::Number(12345.0))])),
+            (string("GameState"), game_state),
+            (string("CurrentRun"), table(0, vec![(string("Depth"), LuabinsValue::Number(3.0))]))
+        ]);
+        let mut lua_state = Vec::new();
+        luabins::encode(&mut lua_state, &[globals]).unwrap();
+        lua_state
+    }
+
+    fn save_for(version: u32) -> HadesSave {
+        let header = match version {
+            16 | 17 => json!({
+                "version": version,
+                "timestamp": 0,
+                "location": "",
+                "runs": 0,
+                "active_meta_points": 0,
+                "active_shrine_points": 0,
+                "unknown0": 0,
+                "unknown1": 0,
+                "unknown2": 0,
+                "god_mode_enabled": false,
+                "hell_mode_enabled": false,
+                "lua_keys": ["NextSeeds", "GameState", "CurrentRun"],
+                "current_map_name": "",
+                "start_next_map": ""
+            }),
+            _ => json!({ "version": version, "header": [] })
+        };
+        HadesSave::from_header_json(&header, lua_state()).unwrap()
+    }
+
+    fn loaded(version: u32) -> (Lua, HadesSave) {
+        let lua = Lua::new();
+        let savedata = save_for(version);
+        initialize(&lua, version).unwrap();
+        load(&lua, &savedata).unwrap();
+        (lua, savedata)
+    }
+
+    #[test]
+    fn untouched_state_saves_unchanged() {
+        for version in [16, 17, 99] {
+            let (lua, mut savedata) = loaded(version);
+            let warnings = save(&lua, &mut savedata).unwrap();
+            assert!(warnings.is_empty(), "v{}: {:?}", version, warnings);
+            assert_eq!(savedata.lua_state(), lua_state(), "v{}", version);
+        }
+    }
+
+    #[test]
+    fn added_keys_follow_the_loaded_ones_sorted() {
+        for version in [16, 17, 99] {
+            let (lua, mut savedata) = loaded(version);
+            lua.context(|lua_ctx| lua_ctx.load("GameState.Delta = 4; GameState.Beta = 2").exec()).unwrap();
+            save(&lua, &mut savedata).unwrap();
+
+            let values = luabins::decode(&mut savedata.lua_state()).unwrap();
+            let game_state = match &values[0] {
+                LuabinsValue::Table(globals) => &globals.entries[1].1,
+                value => panic!("unexpected {:?}", value)
+            };
+            let keys: Vec<LuabinsValue> = match game_state {
+                LuabinsValue::Table(game_state) => game_state.entries.iter().map(|(key, _)| key.clone()).collect(),
+                value => panic!("unexpected {:?}", value)
+            };
+            assert_eq!(keys, [
+                LuabinsValue::Number(2.0),
+                string("Zeta"),
+                LuabinsValue::Number(1.0),
+                string("Alpha"),
+                LuabinsValue::Boolean(false),
+                string("Beta"),
+                string("Delta")
+            ], "v{}", version);
+        }
+    }
+}
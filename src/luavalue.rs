@@ -0,0 +1,257 @@
+//! Conversion between luabins values and values in an rlua interpreter.
+
+use crate::error::{Result, SaveError};
+use crate::luabins;
+use crate::luabins::{LuabinsError, LuabinsTable, LuabinsValue};
+
+use rlua::Value;
+use std::borrow::Cow;
+use std::cmp::Ordering;
+
+// Lua tables don't remember the order their keys were added in, nor the
+// array/hash split luabins recorded for them, so both are kept on the side
+// as a layout record ({keys = {...}, array_size = n, hash_size = m}) in a
+// registry table weakly keyed by table. Saving writes keys back in that
+// order, and reuses the sizes while the keys are unchanged, which makes an
+// unchanged state save to the same bytes it was loaded from.
+const TABLE_LAYOUT: &str = "luavalue_table_layout";
+
+fn layouts<'lua>(context: rlua::Context<'lua>) -> Result<rlua::Table<'lua>> {
+  match context.named_registry_value::<_, Option<rlua::Table>>(TABLE_LAYOUT)? {
+    Some(layouts) => Ok(layouts),
+    None => {
+      let layouts = context.create_table()?;
+      let metatable = context.create_table()?;
+      metatable.set("__mode", "k")?;
+      layouts.set_metatable(Some(metatable));
+      context.set_named_registry_value(TABLE_LAYOUT, layouts.clone())?;
+      Ok(layouts)
+    }
+  }
+}
+
+/// The layout record of the table, if it was loaded.
+pub fn layout<'lua>(context: rlua::Context<'lua>, table: &rlua::Table<'lua>) -> Result<Option<rlua::Table<'lua>>> {
+  Ok(layouts(context)?.raw_get(table.clone())?)
+}
+
+pub fn set_layout<'lua>(context: rlua::Context<'lua>, table: &rlua::Table<'lua>, layout: rlua::Table<'lua>) -> Result<()> {
+  Ok(layouts(context)?.raw_set(table.clone(), layout)?)
+}
+
+// The integer a key is, if it is one.
+fn integer_key(key: &Value) -> Option<i64> {
+  match key {
+    Value::Integer(i) => Some(*i),
+    Value::Number(n) => LuabinsValue::Number(*n).as_integer(),
+    _ => None
+  }
+}
+
+// The array and hash sizes to write for a table with these entries. While
+// its keys are the ones it was loaded with, those sizes are reused as is.
+// Otherwise they're worked out as luabins::sizes_for_keys does.
+fn table_sizes<'lua>(context: rlua::Context<'lua>, table: &rlua::Table<'lua>, keys: &[Value]) -> Result<(i32, i32)> {
+  if let Some(layout) = layout(context, table)? {
+    let loaded_keys: rlua::Table = layout.get("keys")?;
+    let loaded_count = loaded_keys.raw_len() as usize;
+    let mut present = 0;
+    for key in loaded_keys.sequence_values::<Value>() {
+      let value: Value = table.raw_get(key?)?;
+      if let Value::Nil = value {
+        continue;
+      }
+      present += 1;
+    }
+    let unchanged = present == loaded_count && keys.len() == loaded_count;
+    if unchanged {
+      return Ok((layout.get("array_size")?, layout.get("hash_size")?));
+    }
+  }
+  Ok(luabins::sizes_for_keys(keys.iter().map(integer_key)))
+}
+
+// Keys without a recorded order (added since loading) sort after the recorded
+// ones: booleans, then numbers, then strings, then anything else.
+fn compare_keys(a: &Value, b: &Value) -> Ordering {
+  fn rank(key: &Value) -> u8 {
+    match key {
+      Value::Boolean(_) => 0,
+      Value::Integer(_) | Value::Number(_) => 1,
+      Value::String(_) => 2,
+      _ => 3
+    }
+  }
+  fn number(key: &Value) -> f64 {
+    match key {
+      Value::Integer(i) => *i as f64,
+      Value::Number(n) => *n,
+      _ => 0.0
+    }
+  }
+  match (a, b) {
+    (Value::Boolean(a), Value::Boolean(b)) => a.cmp(b),
+    (Value::String(a), Value::String(b)) => a.as_bytes().cmp(b.as_bytes()),
+    _ if rank(a) == 1 && rank(b) == 1 => number(a).total_cmp(&number(b)),
+    _ => rank(a).cmp(&rank(b))
+  }
+}
+
+/// The table's pairs in a deterministic order: loaded keys first, in the
+/// order they were loaded, then any new keys sorted.
+pub fn ordered_pairs<'lua>(context: rlua::Context<'lua>, table: &rlua::Table<'lua>) -> Result<Vec<(Value<'lua>, Value<'lua>)>> {
+  let mut pairs = Vec::new();
+  let seen = context.create_table()?;
+  if let Some(layout) = layout(context, table)? {
+    let keys: rlua::Table = layout.get("keys")?;
+    for key in keys.sequence_values::<Value>() {
+      let key = key?;
+      let value: Value = table.raw_get(key.clone())?;
+      let already_seen: bool = seen.raw_get(key.clone())?;
+      if let Value::Nil = value {
+        continue;
+      }
+      if !already_seen {
+        seen.raw_set(key.clone(), true)?;
+        pairs.push((key, value));
+      }
+    }
+  }
+  let mut new_pairs = Vec::new();
+  for pair in table.clone().pairs::<Value, Value>() {
+    let (key, value) = pair?;
+    let already_seen: bool = seen.raw_get(key.clone())?;
+    if !already_seen {
+      new_pairs.push((key, value));
+    }
+  }
+  new_pairs.sort_by(|(a, _), (b, _)| compare_keys(a, b));
+  pairs.extend(new_pairs);
+  Ok(pairs)
+}
+
+fn is_saved_type(value: &Value) -> bool {
+  matches!(value, Value::Nil | Value::Boolean(_) | Value::Integer(_) | Value::Number(_) | Value::String(_) | Value::Table(_))
+}
+
+/// Tables remember the key order and sizes they were loaded with, so that
+/// saving them again without changes gives back the same bytes.
+pub fn to_lua<'lua>(context: rlua::Context<'lua>, value: &LuabinsValue) -> Result<Value<'lua>> {
+  let lua_value = match value {
+    LuabinsValue::Nil => Value::Nil,
+    LuabinsValue::Boolean(b) => Value::Boolean(*b),
+    LuabinsValue::Number(n) => match value.as_integer() {
+      Some(i) => Value::Integer(i),
+      None => Value::Number(*n)
+    },
+    LuabinsValue::String(s) => Value::String(context.create_string(s)?),
+    LuabinsValue::Table(t) => {
+      let table = context.create_table()?;
+      let keys = context.create_table()?;
+      for (i, (key, value)) in t.entries.iter().enumerate() {
+        let key = to_lua(context, key)?;
+        let value = to_lua(context, value)?;
+        keys.raw_set(i + 1, key.clone())?;
+        table.set(key, value)?;
+      }
+      let layout = context.create_table()?;
+      layout.set("keys", keys)?;
+      layout.set("array_size", t.array_size)?;
+      layout.set("hash_size", t.hash_size)?;
+      set_layout(context, &table, layout)?;
+      Value::Table(table)
+    }
+  };
+  Ok(lua_value)
+}
+
+/// Functions, userdata and threads can't be saved; table entries holding them
+/// are left out. Tables nested deeper than luabins::MAX_DEPTH (including any
+/// that contain themselves) are an error.
+pub fn from_lua<'lua>(context: rlua::Context<'lua>, value: Value<'lua>, depth: usize) -> Result<LuabinsValue<'static>> {
+  let luabins_value = match value {
+    Value::Nil => LuabinsValue::Nil,
+    Value::Boolean(b) => LuabinsValue::Boolean(b),
+    Value::Integer(i) => LuabinsValue::Number(i as f64),
+    Value::Number(n) => LuabinsValue::Number(n),
+    Value::String(s) => LuabinsValue::String(Cow::Owned(s.as_bytes().to_vec())),
+    Value::Table(table) => {
+      if depth >= luabins::MAX_DEPTH {
+        return Err(SaveError::Unencodable(LuabinsError::TooDeep));
+      }
+      let pairs: Vec<(Value, Value)> = ordered_pairs(context, &table)?
+        .into_iter()
+        .filter(|(key, value)| is_saved_type(key) && is_saved_type(value))
+        .collect();
+      let keys: Vec<Value> = pairs.iter().map(|(key, _)| key.clone()).collect();
+      let (array_size, hash_size) = table_sizes(context, &table, &keys)?;
+      let mut entries = Vec::new();
+      for (key, value) in pairs {
+        entries.push((from_lua(context, key, depth + 1)?, from_lua(context, value, depth + 1)?));
+      }
+      LuabinsValue::Table(LuabinsTable {
+        array_size: array_size,
+        hash_size: hash_size,
+        entries: entries
+      })
+    },
+    _ => LuabinsValue::Nil
+  };
+  Ok(luabins_value)
+}
+
+pub fn load<'lua>(context: rlua::Context<'lua>, values: &[LuabinsValue]) -> Result<Vec<Value<'lua>>> {
+  values.iter().map(|value| to_lua(context, value)).collect()
+}
+
+pub fn save<'lua>(context: rlua::Context<'lua>, values: Vec<Value<'lua>>) -> Result<Vec<LuabinsValue<'static>>> {
+  values.into_iter().map(|value| from_lua(context, value, 0)).collect()
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn sizes(value: &LuabinsValue) -> (i32, i32) {
+    match value {
+      LuabinsValue::Table(table) => (table.array_size, table.hash_size),
+      value => panic!("unexpected {:?}", value)
+    }
+  }
+
+  // Keys 1, 2 and 3, split 1 + 2 rather than the 3 + 0 a border would give.
+  fn loaded_table() -> LuabinsValue<'static> {
+    LuabinsValue::Table(LuabinsTable {
+      array_size: 1,
+      hash_size: 2,
+      entries: [1.0, 2.0, 3.0].iter().map(|n| (LuabinsValue::Number(*n), LuabinsValue::Boolean(true))).collect()
+    })
+  }
+
+  #[test]
+  fn unchanged_tables_keep_their_sizes() {
+    rlua::Lua::new().context(|context| {
+      let table = to_lua(context, &loaded_table()).unwrap();
+      assert_eq!(sizes(&from_lua(context, table, 0).unwrap()), (1, 2));
+    });
+  }
+
+  #[test]
+  fn changed_tables_get_new_sizes() {
+    rlua::Lua::new().context(|context| {
+      let table = to_lua(context, &loaded_table()).unwrap();
+      if let Value::Table(table) = &table {
+        table.set(3, Value::Nil).unwrap();
+      }
+      assert_eq!(sizes(&from_lua(context, table, 0).unwrap()), (2, 0));
+    });
+  }
+
+  #[test]
+  fn holes_end_the_array_part() {
+    rlua::Lua::new().context(|context| {
+      let table: Value = context.load("return { [1] = 'a', [2] = 'b', [4] = 'd', [2.5] = 'e' }").eval().unwrap();
+      assert_eq!(sizes(&from_lua(context, table, 0).unwrap()), (2, 2));
+    });
+  }
+}
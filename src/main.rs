@@ -1,18 +1,26 @@
+mod diff;
+mod edit;
+#[cfg(feature = "gui")]
 mod gui;
-mod luabins;
-mod luastate;
-mod read;
+mod info;
+mod luapath;
+mod patch;
 mod repl;
-mod hadesfile;
-mod write;
 
-use anyhow::Result;
+use anyhow::{Context as _, Result};
 use clap::{arg, Command};
-use hadesfile::HadesSave;
-use rlua::{Context, Lua, Value as LuaValue};
-use std::path::{Path, PathBuf};
+use hadessave::{hadesfile, luabins, luajson, luastate, read_file, HadesSave, Warning};
+use rlua::Lua;
 use std::fs;
-use serde_json::{Value, json};
+use std::path::PathBuf;
+use serde_json::Value;
+
+// Warnings go to stderr, to keep stdout for --json and get.
+pub fn print_warnings(warnings: &[Warning]) {
+    for warning in warnings {
+        eprintln!("Warning: {}", warning);
+    }
+}
 
 fn cli() -> Command {
     Command::new("hadessaveeditor")
@@ -20,143 +28,188 @@ fn cli() -> Command {
         .arg(arg!(file: [FILE] "The hades save file to open.").value_parser(clap::value_parser!(PathBuf)))
         .arg(arg!(-r --repl "Starts the command-line repl instead of the gui."))
         .arg(arg!(--json "Dumps the save as json instead of starting the gui."))
+        .arg(arg!(--"json-style" [STYLE] "Either lossless (the default, readable by --from-json) or human.").value_parser(["lossless", "human"]))
+        .arg(arg!(--"from-json" [JSON] "Writes the file from a json dump instead of opening it.").value_parser(clap::value_parser!(PathBuf)))
+        .arg(arg!(--"ignore-checksum" "Opens the file even if its checksum does not match."))
+        .arg(arg!(-f --force "Saves even if the game would not be able to load the result."))
+        .arg(arg!(--convert [VERSION] "Converts the save to another version before opening.").value_parser(clap::value_parser!(u32)))
         .arg(arg!(-s --script [SCRIPT] "Runs the script on the file before opening.").value_parser(clap::value_parser!(PathBuf)))
+        .arg(arg!(-o --output [OUTPUT] "Writes the save here instead of overwriting FILE, or the --json dump instead of printing it.").value_parser(clap::value_parser!(PathBuf)))
+        .subcommand(
+            Command::new("info")
+                .about("Prints a summary of the save")
+                .arg(arg!(file: <FILE> "The hades save file to summarise.").value_parser(clap::value_parser!(PathBuf)))
+                .arg(arg!(--format [FORMAT] "Either text (the default) or json.").value_parser(["text", "json"]))
+        )
+        .subcommand(
+            Command::new("get")
+                .about("Prints the value at a lua path, like GameState.Resources.MetaPoints")
+                .arg(arg!(file: <FILE> "The hades save file to read.").value_parser(clap::value_parser!(PathBuf)))
+                .arg(arg!(path: <PATH> "The lua path of the value."))
+                .arg(arg!(--"ignore-checksum" "Opens the file even if its checksum does not match."))
+        )
+        .subcommand(
+            Command::new("set")
+                .about("Sets the value at a lua path to the result of a lua expression, and saves")
+                .arg(arg!(file: <FILE> "The hades save file to edit.").value_parser(clap::value_parser!(PathBuf)))
+                .arg(arg!(path: <PATH> "The lua path of the value."))
+                .arg(arg!(expression: <EXPRESSION> "The lua expression to set it to."))
+                .arg(arg!(--"ignore-checksum" "Opens the file even if its checksum does not match."))
+                .arg(arg!(-f --force "Saves even if the game would not be able to load the result."))
+                .arg(arg!(-o --output [OUTPUT] "Writes the save here instead of overwriting FILE.").value_parser(clap::value_parser!(PathBuf)))
+        )
+        .subcommand(
+            Command::new("diff")
+                .about("Prints what changed from one save to another")
+                .arg(arg!(old: <OLD> "The save to compare against.").value_parser(clap::value_parser!(PathBuf)))
+                .arg(arg!(new: <NEW> "The changed save.").value_parser(clap::value_parser!(PathBuf)))
+                .arg(arg!(--format [FORMAT] "Either text (the default) or json.").value_parser(["text", "json"]))
+                .arg(arg!(--prefix [PATH] "Only shows changes at or under this lua path, leaving out the header. Can be repeated.").action(clap::ArgAction::Append))
+                .arg(arg!(--"ignore-checksum" "Opens the files even if their checksums do not match."))
+        )
+        .subcommand(
+            Command::new("patch")
+                .about("Generates patches from two saves, and applies them to others")
+                .subcommand_required(true)
+                .subcommand(
+                    Command::new("generate")
+                        .about("Prints the patch that turns one save into another")
+                        .arg(arg!(old: <OLD> "The save before the changes.").value_parser(clap::value_parser!(PathBuf)))
+                        .arg(arg!(new: <NEW> "The save after the changes.").value_parser(clap::value_parser!(PathBuf)))
+                        .arg(arg!(--"ignore-checksum" "Opens the files even if their checksums do not match."))
+                        .arg(arg!(-o --output [OUTPUT] "Writes the patch here instead of printing it.").value_parser(clap::value_parser!(PathBuf)))
+                )
+                .subcommand(
+                    Command::new("apply")
+                        .about("Applies a patch to a save")
+                        .arg(arg!(patch: <PATCH> "The patch to apply.").value_parser(clap::value_parser!(PathBuf)))
+                        .arg(arg!(file: <FILE> "The hades save file to patch.").value_parser(clap::value_parser!(PathBuf)))
+                        .arg(arg!(--"ignore-checksum" "Opens the file even if its checksum does not match."))
+                        .arg(arg!(-f --force "Saves even if the game would not be able to load the result."))
+                        .arg(arg!(-o --output [OUTPUT] "Writes the save here instead of overwriting FILE.").value_parser(clap::value_parser!(PathBuf)))
+                )
+        )
+        .args_conflicts_with_subcommands(true)
         .arg_required_else_help(true)
 }
 
 fn main() -> Result<()> {
     let matches = cli().get_matches();
 
+    match matches.subcommand() {
+        Some(("info", info_matches)) => {
+            let path: &PathBuf = info_matches.get_one("file").expect("required");
+            let format = info_matches.get_one::<String>("format").map_or("text", |format| format.as_str());
+            return info::info(path, format);
+        },
+        Some(("get", get_matches)) => {
+            let path: &PathBuf = get_matches.get_one("file").expect("required");
+            let lua_path: &String = get_matches.get_one("path").expect("required");
+            return edit::get(path, lua_path, get_matches.get_flag("ignore-checksum"));
+        },
+        Some(("set", set_matches)) => {
+            let path: &PathBuf = set_matches.get_one("file").expect("required");
+            let lua_path: &String = set_matches.get_one("path").expect("required");
+            let expression: &String = set_matches.get_one("expression").expect("required");
+            let output: &PathBuf = set_matches.get_one("output").unwrap_or(path);
+            return edit::set(path, output, lua_path, expression, set_matches.get_flag("ignore-checksum"), set_matches.get_flag("force"));
+        },
+        Some(("diff", diff_matches)) => {
+            let old_path: &PathBuf = diff_matches.get_one("old").expect("required");
+            let new_path: &PathBuf = diff_matches.get_one("new").expect("required");
+            let format = diff_matches.get_one::<String>("format").map_or("text", |format| format.as_str());
+            let prefixes: Vec<String> = diff_matches.get_many::<String>("prefix").unwrap_or_default().cloned().collect();
+            return diff::diff(old_path, new_path, format, &prefixes, diff_matches.get_flag("ignore-checksum"));
+        },
+        Some(("patch", patch_matches)) => match patch_matches.subcommand() {
+            Some(("generate", generate_matches)) => {
+                let old_path: &PathBuf = generate_matches.get_one("old").expect("required");
+                let new_path: &PathBuf = generate_matches.get_one("new").expect("required");
+                let output = generate_matches.get_one::<PathBuf>("output").map(|output| output.as_path());
+                return patch::generate(old_path, new_path, output, generate_matches.get_flag("ignore-checksum"));
+            },
+            Some(("apply", apply_matches)) => {
+                let patch_path: &PathBuf = apply_matches.get_one("patch").expect("required");
+                let path: &PathBuf = apply_matches.get_one("file").expect("required");
+                let output: &PathBuf = apply_matches.get_one("output").unwrap_or(path);
+                return patch::apply(patch_path, path, output, apply_matches.get_flag("ignore-checksum"), apply_matches.get_flag("force"));
+            },
+            _ => unreachable!("subcommand_required")
+        },
+        _ => {}
+    }
+
     let path: &PathBuf = matches.get_one("file").expect("required");
+    let output: &PathBuf = matches.get_one("output").unwrap_or(path);
 
     let lua = unsafe {
         Lua::new_with_debug()
     };
 
-    let file = read_file(path)?;
-    let savedata: HadesSave = hadesfile::read(&mut file.as_slice())?;
-    let lua_state = match savedata.clone() {
-        HadesSave::V16(data) => {
-            luastate::initialize_v16(&lua)?;
-            data.lua_state
-        },
-        HadesSave::V17(data) => {
-            luastate::initialize_v17(&lua)?;
-            data.lua_state
+    if let Some(json_path) = matches.get_one::<PathBuf>("from-json") {
+        let document: Value = serde_json::from_slice(&read_file(json_path)?)?;
+        let mut lua_state: Vec<u8> = Vec::new();
+        let values = luajson::load(&document["lua_state"]).context("lua_state")?;
+        luabins::encode(&mut lua_state, &values)?;
+        let mut savedata = HadesSave::from_header_json(&document, lua_state)?;
+        savedata.set_lua_state_tail(luajson::load_tail(&document["lua_state_tail"]).context("lua_state_tail")?);
+        print_warnings(&hadessave::save(output, &savedata, matches.get_flag("force"))?);
+        return Ok(());
+    }
+
+    let mut savedata: HadesSave = hadessave::open(path, matches.get_flag("ignore-checksum"))?;
+    if let Some(version) = matches.get_one::<u32>("convert") {
+        let (mut converted, field_warnings) = hadesfile::convert(&savedata, *version)?;
+        let (lua_state, dropped_globals) = luastate::convert(savedata.lua_state(), *version)?;
+        converted.set_lua_state(lua_state);
+        print_warnings(&field_warnings);
+        for global in dropped_globals {
+            eprintln!("Dropping global {}", global);
         }
-    };
+        // The dropped globals are still listed in the source's lua_keys.
+        print_warnings(&hadesfile::sync_lua_keys(&mut converted)?);
+        savedata = converted;
+    }
+    if let Err(err) = hadesfile::check_size(savedata.lua_state(), savedata.uncompressed_size()) {
+        eprintln!("Warning: {}\nIt can't be saved without --force until it's cut down.", err);
+    }
 
     if matches.get_flag("json") {
-        let mut value = dump_json(savedata)?;
-        value["lua_state"] = lua.context(|lua_ctx| -> Result<Value> {
-            let loaded = luabins::load(&mut lua_state.as_slice(), lua_ctx)?;
-            dump_lua_json_vec(lua_ctx, loaded)
-        })?;
-        println!("{}", value);
+        let mut value = savedata.header_json();
+        let loaded = luabins::decode(&mut savedata.lua_state()).context("lua_state")?;
+        value["lua_state"] = match matches.get_one::<String>("json-style").map(|style| style.as_str()) {
+            Some("human") => luajson::dump_human(&loaded)?,
+            _ => luajson::dump(&loaded)
+        };
+        if !savedata.lua_state_tail().is_empty() {
+            value["lua_state_tail"] = luajson::dump_tail(savedata.lua_state_tail());
+        }
+        match matches.get_one::<PathBuf>("output") {
+            Some(output) => fs::write(output, value.to_string())?,
+            None => println!("{}", value)
+        }
     } else {
-        luastate::load(&lua, &mut lua_state.as_slice())?;
-
-        match matches.get_one::<PathBuf>("script") {
-            Some(script_path) => {
-                let file = read_file(script_path)?;
-                lua.context(|ctx| -> Result<()> {
-                    let chunk = ctx.load(&file);
-                    chunk.exec().map_err(anyhow::Error::new)
-                })?
-            },
-            _ => {}
+        luastate::initialize(&lua, savedata.version())?;
+        luastate::load(&lua, &savedata)?;
+
+        if let Some(script_path) = matches.get_one::<PathBuf>("script") {
+            let file = read_file(script_path)?;
+            lua.context(|ctx| -> Result<()> {
+                let chunk = ctx.load(&file);
+                chunk.exec().map_err(anyhow::Error::new)
+            })?
         }    
 
+        let force = matches.get_flag("force");
         if matches.get_flag("repl") {
-            repl::repl(lua, savedata, path.to_owned())?;
+            repl::repl(lua, savedata, output.to_owned(), force)?;
         } else {
-            gui::gui(lua, savedata, path.to_owned())?;
+            #[cfg(feature = "gui")]
+            gui::gui(lua, savedata, output.to_owned(), force)?;
+            #[cfg(not(feature = "gui"))]
+            anyhow::bail!("built without the gui; use --repl");
         }
     }
 
     Ok(())
 }
-
-fn dump_lua_json_vec(context: Context, values: Vec<LuaValue>) -> Result<Value> {
-    let mut table = json!({});
-    for (i, value) in values.iter().enumerate() {
-        table[format!("[{}]", i)] = dump_lua_json(context, value)?
-    }
-    Ok(table)
-}
-
-fn dump_lua_json(context: Context, value: &LuaValue) -> Result<Value> {
-    match value {
-        LuaValue::Boolean(b) => Ok(json!(b)),
-        LuaValue::Nil => Ok(json!(null)),
-        LuaValue::Integer(i) => Ok(json!(i)),
-        LuaValue::Number(n) => Ok(json!(n)),
-        LuaValue::String(s) => Ok(json!(s.to_str()?)),
-        LuaValue::Table(t) => {
-            let mut table = json!({});
-            t.clone().pairs::<LuaValue, LuaValue>().for_each(|pair| {
-                let pair = pair.unwrap();
-                let key = pair.0;
-                let value = pair.1;
-
-                match key {
-                    LuaValue::String(s) => {
-                        table[s.to_str().unwrap()] = dump_lua_json(context, &value).unwrap()
-                    },
-                    LuaValue::Integer(i) => {
-                        table[format!("[{}]", i)] = dump_lua_json(context, &value).unwrap()
-                    },
-                    _ => {}
-                }
-            });
-            Ok(table)
-        },
-        LuaValue::Function(_) => todo!(),
-        LuaValue::Thread(_) => todo!(),
-        LuaValue::UserData(_) => todo!(),
-        LuaValue::LightUserData(_) => todo!(),
-        LuaValue::Error(_) => todo!(),
-    }
-}
-
-fn dump_json(save: HadesSave) -> Result<Value> {
-    let value = match save {
-        HadesSave::V16(data) => 
-            json!({
-                "timestamp": data.timestamp,
-                "location": data.location,
-                "runs": data.runs,
-                "active_meta_points": data.active_meta_points,
-                "active_shrine_points": data.active_shrine_points,
-                "god_mode_enabled": data.god_mode_enabled,
-                "hell_mode_enabled": data.hell_mode_enabled,
-                "lua_keys": data.lua_keys,
-                "current_map_name": data.current_map_name,
-                "start_next_map": data.start_next_map,
-            }),
-        HadesSave::V17(data) => 
-            json!({
-                "timestamp": data.timestamp,
-                "location": data.location,
-                "padding1": data.padding1,
-                "runs": data.runs,
-                "god_mode_enabled": data.god_mode_enabled,
-                "hell_mode_enabled": data.hell_mode_enabled,
-                "lua_keys": data.lua_keys,
-                "current_map_name": data.current_map_name,
-                "start_next_map": data.start_next_map,
-            }),
-    };
-
-    Ok(value)
-}
-
-const BYTE_ORDER_MARK: &[u8] = "\u{feff}".as_bytes();
-fn read_file<P: AsRef<Path>>(path: P) -> Result<Vec<u8>> {
-  let file = fs::read(path)?;
-  if file.starts_with(BYTE_ORDER_MARK) {
-     Ok(file[3..].to_vec())
-  } else {
-     Ok(file.to_vec())
-  }
-}
@@ -0,0 +1,30 @@
+use crate::print_warnings;
+
+use anyhow::{Context as _, Result};
+use hadessave::{luapatch, read_file};
+use serde_json::Value;
+use std::fs;
+use std::path::Path;
+
+// Prints the patch that turns the old save into the new one, or writes it to
+// output.
+pub fn generate(old_path: &Path, new_path: &Path, output: Option<&Path>, ignore_checksum: bool) -> Result<()> {
+    let old_save = hadessave::open(old_path, ignore_checksum).with_context(|| old_path.display().to_string())?;
+    let new_save = hadessave::open(new_path, ignore_checksum).with_context(|| new_path.display().to_string())?;
+    let patch = luapatch::generate(&old_save, &new_save)?;
+    let patch = serde_json::to_string_pretty(&patch)?;
+    match output {
+        Some(output) => fs::write(output, patch)?,
+        None => println!("{}", patch)
+    }
+    Ok(())
+}
+
+pub fn apply(patch_path: &Path, path: &Path, output: &Path, ignore_checksum: bool, force: bool) -> Result<()> {
+    let patch: Value = serde_json::from_slice(&read_file(patch_path)?).with_context(|| patch_path.display().to_string())?;
+    let mut savedata = hadessave::open(path, ignore_checksum)?;
+    let mut warnings = luapatch::apply(&patch, &mut savedata).with_context(|| patch_path.display().to_string())?;
+    warnings.extend(hadessave::save(output, &savedata, force)?);
+    print_warnings(&warnings);
+    Ok(())
+}
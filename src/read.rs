@@ -1,5 +1,33 @@
+// Everything in a save is little-endian, whatever the host.
+
 use std::convert::TryInto;
-use anyhow::{anyhow, Result};
+use crate::error::SaveError;
+use crate::luabins::LuabinsError;
+
+// Reads fail only by running out of data. Callers turn that into their own
+// error with ?, as SaveError::Truncated or LuabinsError::Truncated.
+pub struct Truncated {
+  needed: usize,
+  available: usize
+}
+
+impl From<Truncated> for SaveError {
+  fn from(truncated: Truncated) -> SaveError {
+    SaveError::Truncated { offset: None, needed: truncated.needed, available: truncated.available }
+  }
+}
+
+impl From<Truncated> for LuabinsError {
+  fn from(truncated: Truncated) -> LuabinsError {
+    LuabinsError::Truncated { needed: truncated.needed, available: truncated.available }
+  }
+}
+
+type Result<T> = std::result::Result<T, Truncated>;
+
+fn truncated(needed: usize, available: usize) -> Truncated {
+  Truncated { needed: needed, available: available }
+}
 
 pub fn byte(loadstate: &mut &[u8]) -> Result<u8> {
   match loadstate.split_first() {
@@ -7,7 +35,7 @@ pub fn byte(loadstate: &mut &[u8]) -> Result<u8> {
       *loadstate = rest;
       Ok(*first)
     },
-    None => Err(anyhow!("read::byte failed, not enough data (needed 1 byte, 0 available)"))
+    None => Err(truncated(1, 0))
   }
 }
 
@@ -15,9 +43,9 @@ pub fn i32(loadstate: &mut &[u8]) -> Result<i32> {
     if loadstate.len() >= 4 {
         let (i32_bytes, rest) = loadstate.split_at(4);
         *loadstate = rest;
-        Ok(i32::from_ne_bytes(i32_bytes.try_into().unwrap()))
+        Ok(i32::from_le_bytes(i32_bytes.try_into().unwrap()))
     } else {
-        Err(anyhow!("read::i32 failed, not enough data (needed 4 bytes, {} available)", loadstate.len()))
+        Err(truncated(4, loadstate.len()))
     }
 }
 
@@ -25,9 +53,9 @@ pub fn u32(loadstate: &mut &[u8]) -> Result<u32> {
     if loadstate.len() >= 4 {
         let (u32_bytes, rest) = loadstate.split_at(4);
         *loadstate = rest;
-        Ok(u32::from_ne_bytes(u32_bytes.try_into().unwrap()))
+        Ok(u32::from_le_bytes(u32_bytes.try_into().unwrap()))
     } else {
-        Err(anyhow!("read::u32 failed, not enough data (needed 4 bytes, {} available)", loadstate.len()))
+        Err(truncated(4, loadstate.len()))
     }
 }
 
@@ -35,9 +63,9 @@ pub fn u64(loadstate: &mut &[u8]) -> Result<u64> {
     if loadstate.len() >= 8 {
         let (u64_bytes, rest) = loadstate.split_at(8);
         *loadstate = rest;
-        Ok(u64::from_ne_bytes(u64_bytes.try_into().unwrap()))
+        Ok(u64::from_le_bytes(u64_bytes.try_into().unwrap()))
     } else {
-        Err(anyhow!("read::u64 failed, not enough data (needed 8 bytes, {} available)", loadstate.len()))
+        Err(truncated(8, loadstate.len()))
     }
 }
 
@@ -45,18 +73,18 @@ pub fn f64(loadstate: &mut &[u8]) -> Result<f64> {
     if loadstate.len() >= 8 {
         let (f64_bytes, rest) = loadstate.split_at(8);
         *loadstate = rest;
-        Ok(f64::from_ne_bytes(f64_bytes.try_into().unwrap()))
+        Ok(f64::from_le_bytes(f64_bytes.try_into().unwrap()))
     } else {
-        Err(anyhow!("read::f64 failed, not enough data (needed 8 bytes, {} available)", loadstate.len()))
+        Err(truncated(8, loadstate.len()))
     }
 }
 
-pub fn bytes<'a>(loadstate: &'a mut &[u8], len: usize) -> Result<&'a [u8]> {
+pub fn bytes<'a>(loadstate: &mut &'a [u8], len: usize) -> Result<&'a [u8]> {
     if loadstate.len() >= len {
         let (bytes, rest) = loadstate.split_at(len);
         *loadstate = rest;
         Ok(bytes)
     } else {
-        Err(anyhow!("read::bytes failed, not enough data (needed {} bytes, {} available)", len, loadstate.len()))
+        Err(truncated(len, loadstate.len()))
     }
 }
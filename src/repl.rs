@@ -1,15 +1,13 @@
-use crate::hadesfile;
-use crate::luastate;
+use crate::print_warnings;
 
 use anyhow::Result;
-use hadesfile::HadesSave;
+use hadessave::{luastate, HadesSave};
 use rlua::{Function, Lua, MultiValue};
 use rustyline::error::ReadlineError;
 use rustyline::Editor;
-use std::fs;
 use std::path::PathBuf;
 
-pub fn repl(lua: Lua, savedata: HadesSave, path: PathBuf) -> Result<()> {
+pub fn repl(lua: Lua, mut savedata: HadesSave, path: PathBuf, force: bool) -> Result<()> {
     let mut editor = Editor::<()>::new()?;
     loop {
         let readline = editor.readline(">> ");
@@ -23,7 +21,7 @@ pub fn repl(lua: Lua, savedata: HadesSave, path: PathBuf) -> Result<()> {
                 lua.context(|lua_ctx| -> Result<()> {
                     let result: MultiValue = lua_ctx.load(&line).eval()?;
                     let print: Function = lua_ctx.globals().get("print")?;
-                    print.call(result)?;
+                    print.call::<_, ()>(result)?;
                     Ok(())
                 })?
             },
@@ -32,18 +30,9 @@ pub fn repl(lua: Lua, savedata: HadesSave, path: PathBuf) -> Result<()> {
             },
             Err(ReadlineError::Eof) => { 
                 println!("Saving {}", path.display());
-                let mut savedata: HadesSave = savedata.clone();
-                let lua_state = luastate::save(&lua)?;
-                match savedata {
-                    HadesSave::V16(ref mut data) => {
-                        data.lua_state = lua_state
-                    },
-                    HadesSave::V17(ref mut data) => {
-                        data.lua_state = lua_state
-                    }
-                }
-                let outfile = hadesfile::write(&savedata)?;
-                fs::write(&path, outfile)?;
+                let mut warnings = luastate::save(&lua, &mut savedata)?;
+                warnings.extend(hadessave::save(&path, &savedata, force)?);
+                print_warnings(&warnings);
                 println!("Goodbye!");
                 break
             },